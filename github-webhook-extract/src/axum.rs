@@ -4,13 +4,13 @@ use axum::{
     async_trait,
     body::{Body, Bytes},
     extract::FromRequest,
-    http::{self, StatusCode},
+    http::{self, HeaderMap, StatusCode},
 };
 use serde_json::json;
 use tracing::instrument;
 use uuid::Uuid;
 
-use crate::GithubPayload;
+use crate::{BitbucketPayload, GiteaPayload, GithubPayload, VerifyError};
 
 /// Extract a github event from a request
 #[async_trait]
@@ -23,63 +23,129 @@ where
     #[instrument(skip_all)]
     async fn from_request(req: http::Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
         // get information from headers
-        let event: String = req
-            .headers()
-            .get("X-Github-Event")
-            .and_then(|v| v.to_str().ok())
+        let event: String = header(req.headers(), &["X-Github-Event"])
             .ok_or(StatusCode::BAD_REQUEST)?
             .to_string();
         tracing::info!("parsing event: {}", event);
-        let guid = req
-            .headers()
-            .get("X-Github-Delivery")
-            .and_then(|v| v.to_str().ok())
+        let guid = header(req.headers(), &["X-Github-Delivery"])
             .and_then(|s| Uuid::from_str(s).ok())
             .ok_or(StatusCode::BAD_REQUEST)?;
         tracing::debug!(?guid);
-        let signature_sha1: Option<String> = req
-            .headers()
-            .get("X-Hub-Signature")
-            .and_then(|v| v.to_str().ok())
-            .map(|v| v.to_lowercase());
-        let signature_sha256: Option<String> = req
-            .headers()
-            .get("X-Hub-Signature-256")
-            .and_then(|v| v.to_str().ok())
-            .map(|v| v.to_lowercase());
-
-        // get body
-        let content_type = req
-            .headers()
-            .get("content-type")
-            .and_then(|v| v.to_str().ok())
-            .map(|v| v.to_lowercase())
+        let signature_sha1: Option<String> =
+            header(req.headers(), &["X-Hub-Signature"]).map(|v| v.to_lowercase());
+        let signature_sha256: Option<String> =
+            header(req.headers(), &["X-Hub-Signature-256"]).map(|v| v.to_lowercase());
+
+        let (raw_bytes, json) = read_body(req, state, &event).await?;
+
+        crate::verify(guid, signature_sha1, signature_sha256, raw_bytes, &json).map_err(rejection)
+    }
+}
+
+/// Extract a gitea or forgejo event from a request
+#[async_trait]
+impl<S> FromRequest<S, Body> for GiteaPayload
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    #[instrument(skip_all)]
+    async fn from_request(req: http::Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        // forgejo sends both its own headers and the gitea ones
+        let event: String = header(req.headers(), &["X-Forgejo-Event", "X-Gitea-Event"])
+            .ok_or(StatusCode::BAD_REQUEST)?
+            .to_string();
+        tracing::info!("parsing event: {}", event);
+        let guid = header(req.headers(), &["X-Forgejo-Delivery", "X-Gitea-Delivery"])
+            .and_then(|s| Uuid::from_str(s).ok())
+            .ok_or(StatusCode::BAD_REQUEST)?;
+        tracing::debug!(?guid);
+        let signature: Option<String> =
+            header(req.headers(), &["X-Forgejo-Signature", "X-Gitea-Signature"])
+                .map(|v| v.to_lowercase());
+
+        let (raw_bytes, json) = read_body(req, state, &event).await?;
+
+        crate::verify_gitea(guid, signature, raw_bytes, &json).map_err(rejection)
+    }
+}
+
+/// Extract a bitbucket cloud or server event from a request
+#[async_trait]
+impl<S> FromRequest<S, Body> for BitbucketPayload
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    #[instrument(skip_all)]
+    async fn from_request(req: http::Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let event: String = header(req.headers(), &["X-Event-Key"])
+            .ok_or(StatusCode::BAD_REQUEST)?
+            .to_string();
+        tracing::info!("parsing event: {}", event);
+        // cloud sends `X-Request-UUID`, server sends `X-Request-Id`
+        let guid = header(req.headers(), &["X-Request-UUID", "X-Request-Id"])
+            .and_then(|s| Uuid::from_str(s).ok())
             .ok_or(StatusCode::BAD_REQUEST)?;
-        tracing::debug!(?content_type);
-        let raw_bytes = Bytes::from_request(req, state)
-            .await
-            .map_err(|_| StatusCode::BAD_REQUEST)?;
-        let json = if content_type == "application/json" {
-            let json: serde_json::Value = serde_json::from_slice(&raw_bytes).map_err(|e| {
-                tracing::error!(?e);
-                StatusCode::BAD_REQUEST
-            })?;
-            serde_json::to_string(&json!({ event: json })).map_err(|_| StatusCode::BAD_REQUEST)?
-        } else {
-            let form: serde_json::Value =
-                serde_urlencoded::from_bytes(&raw_bytes).map_err(|_| StatusCode::BAD_REQUEST)?;
-            serde_json::to_string(&json!({ event: form })).map_err(|_| StatusCode::BAD_REQUEST)?
-        };
-
-        let res = crate::verify(guid, signature_sha1, signature_sha256, raw_bytes, &json);
-        use crate::VerifyError::*;
-        match res {
-            Ok(payload) => Ok(payload),
-            Err(TokenMissing | HmacCreation) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-            Err(Sha1ParseError | Sha256ParseError | HexParseError | EventParseError) => {
-                Err(StatusCode::BAD_REQUEST)
-            }
-            Err(NotVerified) => Err(StatusCode::UNAUTHORIZED),
+        tracing::debug!(?guid);
+        let signature: Option<String> =
+            header(req.headers(), &["X-Hub-Signature"]).map(|v| v.to_lowercase());
+
+        let (raw_bytes, json) = read_body(req, state, &event).await?;
+
+        crate::verify_bitbucket(guid, signature, raw_bytes, &json).map_err(rejection)
+    }
+}
+
+/// get the first of `names` present in the headers
+fn header<'a>(headers: &'a HeaderMap, names: &[&str]) -> Option<&'a str> {
+    names
+        .iter()
+        .find_map(|name| headers.get(*name).and_then(|v| v.to_str().ok()))
+}
+
+/// read the raw body and convert it to json in the form `{ event: body }`
+async fn read_body<S>(
+    req: http::Request<Body>,
+    state: &S,
+    event: &str,
+) -> Result<(Bytes, String), StatusCode>
+where
+    S: Send + Sync,
+{
+    let content_type = header(req.headers(), &["content-type"])
+        .map(|v| v.to_lowercase())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    tracing::debug!(?content_type);
+    let raw_bytes = Bytes::from_request(req, state)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    // bitbucket server adds a charset
+    let json = if content_type.split(';').next() == Some("application/json") {
+        let json: serde_json::Value = serde_json::from_slice(&raw_bytes).map_err(|e| {
+            tracing::error!(?e);
+            StatusCode::BAD_REQUEST
+        })?;
+        serde_json::to_string(&json!({ event: json })).map_err(|_| StatusCode::BAD_REQUEST)?
+    } else {
+        let form: serde_json::Value =
+            serde_urlencoded::from_bytes(&raw_bytes).map_err(|_| StatusCode::BAD_REQUEST)?;
+        serde_json::to_string(&json!({ event: form })).map_err(|_| StatusCode::BAD_REQUEST)?
+    };
+
+    Ok((raw_bytes, json))
+}
+
+/// Status code to reject a request with when verifying its payload fails
+pub fn rejection(e: VerifyError) -> StatusCode {
+    use crate::VerifyError::*;
+    match e {
+        TokenMissing | HmacCreation => StatusCode::INTERNAL_SERVER_ERROR,
+        Sha1ParseError | Sha256ParseError | HexParseError | EventParseError => {
+            StatusCode::BAD_REQUEST
         }
+        NotVerified | SignatureMissing => StatusCode::UNAUTHORIZED,
     }
 }
@@ -0,0 +1,221 @@
+//! Bitbucket Cloud and Bitbucket Server webhooks. Refer to the
+//! [cloud](https://support.atlassian.com/bitbucket-cloud/docs/event-payloads/) and
+//! [server](https://confluence.atlassian.com/bitbucketserver/event-payload-938025882.html)
+//! docs for the payloads.
+use bytes::Bytes;
+use hmac::Hmac;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::VerifyError;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BitbucketPayload {
+    pub guid: Uuid,
+    pub signature: Option<String>,
+    pub event: BitbucketEvent,
+}
+
+/// supported events, named by the `X-Event-Key` header
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum BitbucketEvent {
+    /// `repo:push` from bitbucket cloud
+    #[serde(rename = "repo:push")]
+    CloudPush(CloudPush),
+    /// `repo:refs_changed` from bitbucket server
+    #[serde(rename = "repo:refs_changed")]
+    ServerPush(ServerPush),
+    /// `diagnostics:ping` sent by bitbucket server's "test connection"
+    #[serde(rename = "diagnostics:ping")]
+    Ping {},
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CloudPush {
+    pub push: CloudChanges,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CloudChanges {
+    pub changes: Vec<CloudChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CloudChange {
+    /// `None` if the ref was deleted
+    pub new: Option<CloudRef>,
+    /// `None` if the ref was created
+    pub old: Option<CloudRef>,
+    #[serde(default)]
+    pub forced: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CloudRef {
+    /// `branch` or `tag`
+    pub r#type: String,
+    pub name: String,
+    pub target: CloudTarget,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CloudTarget {
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ServerPush {
+    pub changes: Vec<ServerChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerChange {
+    /// full name of the ref, e.g. `refs/heads/main`
+    pub ref_id: String,
+    pub from_hash: String,
+    pub to_hash: String,
+    /// `ADD`, `UPDATE` or `DELETE`
+    pub r#type: String,
+}
+
+impl CloudRef {
+    /// full name of the ref, e.g. `refs/heads/main`
+    pub fn full_name(&self) -> String {
+        match self.r#type.as_str() {
+            "tag" => format!("refs/tags/{}", self.name),
+            _ => format!("refs/heads/{}", self.name),
+        }
+    }
+}
+
+/// Verify and parse a bitbucket cloud or server payload. The secret is read
+/// from the `BITBUCKET_TOKEN` environment variable, unsigned payloads are only
+/// accepted when it isn't set.
+///
+/// ## fields:
+/// * `guid`: value of `X-Request-UUID` (cloud) or `X-Request-Id` (server) header
+/// * `signature`: value of `X-Hub-Signature` header
+/// * `bytes`: raw body of the request
+/// * `json`: body of the request in json form, keyed by the `X-Event-Key` header
+#[instrument(skip_all)]
+pub fn verify_bitbucket(
+    guid: Uuid,
+    signature: Option<String>,
+    bytes: Bytes,
+    json: &str,
+) -> Result<BitbucketPayload, VerifyError> {
+    match &signature {
+        Some(sha256) => {
+            tracing::debug!("using sha256");
+            let signature = sha256
+                .split_once('=')
+                .ok_or(VerifyError::Sha256ParseError)?
+                .1;
+            crate::verify_hmac::<Hmac<Sha256>>("BITBUCKET_TOKEN", &bytes, signature)?;
+        }
+        None if std::env::var_os("BITBUCKET_TOKEN").is_some() => {
+            tracing::warn!("payload isn't signed but BITBUCKET_TOKEN is set");
+            return Err(VerifyError::SignatureMissing);
+        }
+        None => tracing::debug!("no signature verification"),
+    }
+
+    let event = crate::parse_event(json)?;
+
+    tracing::debug!("finished extracting bitbucket payload");
+    Ok(BitbucketPayload {
+        guid,
+        signature,
+        event,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{check_signatures, without_secret};
+
+    const CLOUD_PUSH: &str = include_str!("../testdata/bitbucket-cloud-push.json");
+    const SERVER_PUSH: &str = include_str!("../testdata/bitbucket-server-refs-changed.json");
+
+    /// verify `body` like the extractor does, as `{ event_key: body }` json
+    fn verify(
+        event_key: &str,
+        signature: Option<String>,
+        body: &str,
+    ) -> Result<BitbucketPayload, VerifyError> {
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        let json = serde_json::json!({ event_key: json }).to_string();
+        verify_bitbucket(Uuid::nil(), signature, Bytes::from(body.to_string()), &json)
+    }
+
+    #[test]
+    fn signatures() {
+        check_signatures("BITBUCKET_TOKEN", "sha256=", CLOUD_PUSH, |signature| {
+            verify("repo:push", signature, CLOUD_PUSH)
+        });
+        check_signatures("BITBUCKET_TOKEN", "sha256=", SERVER_PUSH, |signature| {
+            verify("repo:refs_changed", signature, SERVER_PUSH)
+        });
+    }
+
+    #[test]
+    fn cloud_push() {
+        let _env = without_secret("BITBUCKET_TOKEN");
+        let payload = verify("repo:push", None, CLOUD_PUSH).unwrap();
+        let BitbucketEvent::CloudPush(push) = payload.event else {
+            panic!("not a cloud push: {:?}", payload.event);
+        };
+        let [update, deleted] = &push.push.changes[..] else {
+            panic!("expected two changes: {:?}", push.push.changes);
+        };
+
+        assert_eq!(update.new.as_ref().unwrap().full_name(), "refs/heads/main");
+        assert_eq!(
+            update.old.as_ref().unwrap().target.hash,
+            "69276a2dcf41af30db542f97a5f75cdac86f1197"
+        );
+        assert_eq!(
+            update.new.as_ref().unwrap().target.hash,
+            "056f7d95bf8857db90986202dc9ed3e6d94f55a8"
+        );
+        assert!(!update.forced);
+
+        assert!(deleted.new.is_none());
+        assert_eq!(
+            deleted.old.as_ref().unwrap().full_name(),
+            "refs/heads/old-feature"
+        );
+    }
+
+    #[test]
+    fn server_refs_changed() {
+        let _env = without_secret("BITBUCKET_TOKEN");
+        let payload = verify("repo:refs_changed", None, SERVER_PUSH).unwrap();
+        assert_eq!(
+            payload.event,
+            BitbucketEvent::ServerPush(ServerPush {
+                changes: vec![ServerChange {
+                    ref_id: "refs/heads/master".to_string(),
+                    from_hash: "ecddabb624f6f5ba43816f5926e580a5f680a932".to_string(),
+                    to_hash: "178864a7d521b6f5e720b386b2c2b0ef8563e0dc".to_string(),
+                    r#type: "UPDATE".to_string(),
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn ping_and_unknown_events() {
+        let _env = without_secret("BITBUCKET_TOKEN");
+        let payload = verify("diagnostics:ping", None, r#"{"test": true}"#).unwrap();
+        assert_eq!(payload.event, BitbucketEvent::Ping {});
+        assert!(matches!(
+            verify("pullrequest:created", None, r#"{}"#),
+            Err(VerifyError::EventParseError)
+        ));
+    }
+}
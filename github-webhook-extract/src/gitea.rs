@@ -0,0 +1,107 @@
+//! Gitea and Forgejo webhooks. Both forges send github compatible payloads
+//! but use their own headers and signature format.
+use bytes::Bytes;
+use hmac::Hmac;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{Event, VerifyError};
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GiteaPayload {
+    pub guid: Uuid,
+    pub signature: Option<String>,
+    pub event: Event,
+}
+
+/// Verify and parse a gitea or forgejo payload. The secret is read from the
+/// `GITEA_TOKEN` environment variable, unsigned payloads are only accepted
+/// when it isn't set.
+///
+/// ## fields:
+/// * `guid`: value of `X-Gitea-Delivery` (or `X-Forgejo-Delivery`) header
+/// * `signature`: value of `X-Gitea-Signature` (or `X-Forgejo-Signature`) header,
+/// a hex encoded hmac sha256 without the `sha256=` prefix
+/// * `bytes`: raw body of the request
+/// * `json`: body of the request in json form
+#[instrument(skip_all)]
+pub fn verify_gitea(
+    guid: Uuid,
+    signature: Option<String>,
+    bytes: Bytes,
+    json: &str,
+) -> Result<GiteaPayload, VerifyError> {
+    match &signature {
+        Some(sha256) => {
+            tracing::debug!("using sha256");
+            crate::verify_hmac::<Hmac<Sha256>>("GITEA_TOKEN", &bytes, sha256)?;
+        }
+        None if std::env::var_os("GITEA_TOKEN").is_some() => {
+            tracing::warn!("payload isn't signed but GITEA_TOKEN is set");
+            return Err(VerifyError::SignatureMissing);
+        }
+        None => tracing::debug!("no signature verification"),
+    }
+
+    let event = crate::parse_event(json)?;
+
+    tracing::debug!("finished extracting gitea payload");
+    Ok(GiteaPayload {
+        guid,
+        signature,
+        event,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{check_signatures, sign, with_secret};
+
+    const PUSH: &str = include_str!("../testdata/gitea-push.json");
+    const DELETE: &str = include_str!("../testdata/gitea-delete.json");
+
+    /// verify `body` like the extractor does, as `{ event: body }` json
+    fn verify(signature: Option<String>, body: &str) -> Result<GiteaPayload, VerifyError> {
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        let json = serde_json::json!({ "push": json }).to_string();
+        verify_gitea(Uuid::nil(), signature, Bytes::from(body.to_string()), &json)
+    }
+
+    #[test]
+    fn signatures() {
+        check_signatures("GITEA_TOKEN", "", PUSH, |signature| verify(signature, PUSH));
+    }
+
+    #[test]
+    fn push() {
+        let _env = with_secret("GITEA_TOKEN");
+        let payload = verify(Some(sign("secret", PUSH)), PUSH).unwrap();
+        let Event::Push {
+            r#ref,
+            after,
+            forced,
+            head_commit,
+            ..
+        } = payload.event
+        else {
+            panic!("not a push: {:?}", payload.event);
+        };
+        assert_eq!(r#ref, "refs/heads/main");
+        assert_eq!(after, "a3f1c07d8e1b8e4c1c7a7b5f2f1a6b3d9c0e4f21");
+        assert!(!forced);
+        assert!(head_commit.is_some());
+    }
+
+    #[test]
+    fn branch_deleted() {
+        let _env = with_secret("GITEA_TOKEN");
+        let payload = verify(Some(sign("secret", DELETE)), DELETE).unwrap();
+        let Event::Push { head_commit, .. } = payload.event else {
+            panic!("not a push: {:?}", payload.event);
+        };
+        assert!(head_commit.is_none());
+    }
+}
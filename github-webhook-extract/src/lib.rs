@@ -1,6 +1,8 @@
 //! # Github Webhook
 //!
-//! Contains types for github webhooks
+//! Contains types for github webhooks. Gitea and Forgejo send github
+//! compatible payloads, which are verified with [`verify_gitea`]. Bitbucket
+//! Cloud and Server pushes are verified with [`verify_bitbucket`].
 //!
 //! ## Features
 //!
@@ -8,15 +10,22 @@
 
 #[cfg(feature = "axum")]
 mod axum;
+#[cfg(feature = "axum")]
+pub use axum::rejection;
+mod bitbucket;
+mod gitea;
+#[cfg(test)]
+mod test_support;
 mod types;
 
+pub use bitbucket::*;
 use bytes::Bytes;
+pub use gitea::*;
 use std::env;
 pub use types::*;
 
-use digest::CtOutput;
-use generic_array::GenericArray;
-use hmac::{Hmac, Mac};
+use hmac::{digest::KeyInit, Hmac, Mac};
+use serde::de::DeserializeOwned;
 use sha1::Sha1;
 use sha2::Sha256;
 use thiserror::Error;
@@ -24,7 +33,9 @@ use tracing::instrument;
 use uuid::Uuid;
 
 /// Verify and parse a github payload. Pass your parsed
-/// data from your web library for parsing and return errors.
+/// data from your web library for parsing and return errors. The secret is
+/// read from the `GITHUB_TOKEN` environment variable, unsigned payloads are
+/// only accepted when it isn't set.
 ///
 /// ## fields:
 /// * `guid`: value of `X-GitHub-Delivery` header
@@ -44,63 +55,25 @@ pub fn verify(
     match (&signature_sha1, &signature_sha256) {
         (Some(sha1), None) => {
             tracing::debug!("using sha1");
-            let token = env::var("GITHUB_TOKEN").map_err(|_| {
-                tracing::error!("secret github token is missing");
-                VerifyError::TokenMissing
-            })?;
-
-            let mut mac = Hmac::<Sha1>::new_from_slice(token.as_bytes()).map_err(|e| {
-                tracing::error!("error creating hmac: {:?}", e);
-                VerifyError::HmacCreation
-            })?;
-            mac.update(&bytes);
-            let result = mac.finalize();
-            let signature = hex::decode(sha1.split_once('=').ok_or(VerifyError::Sha1ParseError)?.1)
-                .map_err(|e| {
-                    tracing::debug!(?e);
-                    VerifyError::HexParseError
-                })?;
-
-            if result != CtOutput::new(*GenericArray::from_slice(&signature)) {
-                return Err(VerifyError::NotVerified);
-            }
+            let signature = sha1.split_once('=').ok_or(VerifyError::Sha1ParseError)?.1;
+            verify_hmac::<Hmac<Sha1>>("GITHUB_TOKEN", &bytes, signature)?;
         }
         (_, Some(sha256)) => {
             tracing::debug!("using sha256");
-            let token = env::var("GITHUB_TOKEN").map_err(|_| {
-                tracing::error!("secret github token is missing");
-                VerifyError::TokenMissing
-            })?;
-
-            let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes()).map_err(|e| {
-                tracing::error!("error creating hmac: {:?}", e);
-                VerifyError::HmacCreation
-            })?;
-            mac.update(&bytes);
-            let result = mac.finalize();
-            let signature = hex::decode(
-                sha256
-                    .split_once('=')
-                    .ok_or(VerifyError::Sha256ParseError)?
-                    .1,
-            )
-            .map_err(|e| {
-                tracing::debug!(?e);
-                VerifyError::HexParseError
-            })?;
-
-            if result != CtOutput::new(*GenericArray::from_slice(&signature)) {
-                return Err(VerifyError::NotVerified);
-            }
+            let signature = sha256
+                .split_once('=')
+                .ok_or(VerifyError::Sha256ParseError)?
+                .1;
+            verify_hmac::<Hmac<Sha256>>("GITHUB_TOKEN", &bytes, signature)?;
+        }
+        (None, None) if env::var_os("GITHUB_TOKEN").is_some() => {
+            tracing::warn!("payload isn't signed but GITHUB_TOKEN is set");
+            return Err(VerifyError::SignatureMissing);
         }
         (None, None) => tracing::debug!("no signature verification"),
     }
 
-    let deserializer = &mut serde_json::Deserializer::from_str(json);
-    let event: Event = serde_path_to_error::deserialize(deserializer).map_err(|e| {
-        tracing::warn!("failed to deserialize event: {}", e);
-        VerifyError::EventParseError
-    })?;
+    let event = parse_event(json)?;
 
     tracing::debug!("finished extracting github payload");
     Ok(GithubPayload {
@@ -111,6 +84,41 @@ pub fn verify(
     })
 }
 
+/// Check a hex encoded hmac `signature` of `bytes` using the secret
+/// stored in the `token_var` environment variable. Useful for verifying
+/// payloads from other providers.
+pub fn verify_hmac<M>(token_var: &str, bytes: &[u8], signature: &str) -> Result<(), VerifyError>
+where
+    M: Mac + KeyInit,
+{
+    let token = env::var(token_var).map_err(|_| {
+        tracing::error!("secret token {} is missing", token_var);
+        VerifyError::TokenMissing
+    })?;
+
+    let mut mac = <M as Mac>::new_from_slice(token.as_bytes()).map_err(|e| {
+        tracing::error!("error creating hmac: {:?}", e);
+        VerifyError::HmacCreation
+    })?;
+    mac.update(bytes);
+    let signature = hex::decode(signature).map_err(|e| {
+        tracing::debug!(?e);
+        VerifyError::HexParseError
+    })?;
+
+    mac.verify_slice(&signature)
+        .map_err(|_| VerifyError::NotVerified)
+}
+
+/// Parse an event from json in the form `{ "<event name>": <payload> }`
+pub(crate) fn parse_event<T: DeserializeOwned>(json: &str) -> Result<T, VerifyError> {
+    let deserializer = &mut serde_json::Deserializer::from_str(json);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        tracing::warn!("failed to deserialize event: {}", e);
+        VerifyError::EventParseError
+    })
+}
+
 /// Error verifying a github payload
 #[derive(Debug, Error)]
 pub enum VerifyError {
@@ -126,6 +134,33 @@ pub enum VerifyError {
     HexParseError,
     #[error("payload not verified correctly")]
     NotVerified,
+    #[error("payload isn't signed but a secret is set")]
+    SignatureMissing,
     #[error("could not parse event")]
     EventParseError,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::check_signatures;
+
+    const PING: &str = include_str!("../testdata/github-ping.json");
+
+    fn verify_ping(sha256: Option<String>) -> Result<GithubPayload, VerifyError> {
+        let json: serde_json::Value = serde_json::from_str(PING).unwrap();
+        let json = serde_json::json!({ "ping": json }).to_string();
+        verify(
+            Uuid::nil(),
+            None,
+            sha256,
+            Bytes::from_static(PING.as_bytes()),
+            &json,
+        )
+    }
+
+    #[test]
+    fn signatures() {
+        check_signatures("GITHUB_TOKEN", "sha256=", PING, verify_ping);
+    }
+}
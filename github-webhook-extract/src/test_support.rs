@@ -0,0 +1,60 @@
+//! Helpers shared by the tests of each provider
+use std::{
+    env,
+    fmt::Debug,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::VerifyError;
+
+/// the providers' secrets are read from the environment, so tests changing
+/// them can't run at the same time
+static ENV: Mutex<()> = Mutex::new(());
+
+/// hold the environment for the rest of the test, with `token_var` set to
+/// `secret`
+pub fn with_secret(token_var: &str) -> MutexGuard<'static, ()> {
+    let env = ENV.lock().unwrap_or_else(PoisonError::into_inner);
+    env::set_var(token_var, "secret");
+    env
+}
+
+/// hold the environment for the rest of the test, with `token_var` unset
+pub fn without_secret(token_var: &str) -> MutexGuard<'static, ()> {
+    let env = ENV.lock().unwrap_or_else(PoisonError::into_inner);
+    env::remove_var(token_var);
+    env
+}
+
+/// hex encoded hmac sha256 of `body`
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// check a provider verifies signed deliveries of `body` against the secret in
+/// `token_var`, and only accepts unsigned ones when the secret isn't set.
+/// `prefix` is put in front of the hex signature, e.g. `sha256=`
+pub fn check_signatures<T: Debug>(
+    token_var: &str,
+    prefix: &str,
+    body: &str,
+    verify: impl Fn(Option<String>) -> Result<T, VerifyError>,
+) {
+    let _env = with_secret(token_var);
+    let signed = |secret| Some(format!("{prefix}{}", sign(secret, body)));
+
+    verify(signed("secret")).unwrap();
+    assert!(matches!(
+        verify(signed("other")),
+        Err(VerifyError::NotVerified)
+    ));
+    assert!(matches!(verify(None), Err(VerifyError::SignatureMissing)));
+
+    env::remove_var(token_var);
+    verify(None).unwrap();
+}
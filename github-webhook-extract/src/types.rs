@@ -90,13 +90,19 @@ pub enum Event {
         base_ref: Option<String>,
         before: String,
         commits: Vec<Commit>,
+        // gitea calls this `compare_url`
+        #[serde(alias = "compare_url")]
         compare: String,
+        // gitea doesn't send `created`, `deleted` or `forced`
+        #[serde(default)]
         created: bool,
+        #[serde(default)]
         deleted: bool,
         enterprise: Enterprise,
+        #[serde(default)]
         forced: bool,
-        // box for clippy
-        head_commit: Box<HeadCommit>,
+        // box for clippy, gitea sends null when a branch is deleted
+        head_commit: Option<Box<HeadCommit>>,
         installation: Installation,
         organization: Organization,
         pusher: Pusher,
@@ -241,12 +247,14 @@ pub struct Commit {
     added: Option<Vec<String>>,
     author: Author,
     committer: Committer,
+    #[serde(default)]
     distinct: bool,
     id: String,
     message: String,
     modified: Option<Vec<String>>,
     removed: Option<Vec<String>>,
     timestamp: String,
+    #[serde(default)]
     tree_id: String,
     url: String,
 }
@@ -272,12 +280,14 @@ pub struct HeadCommit {
     added: Option<Vec<String>>,
     author: Author,
     committer: Committer,
+    #[serde(default)]
     distinct: bool,
     id: String,
     message: String,
     modified: Option<Vec<String>>,
     removed: Option<Vec<String>>,
     timestamp: String,
+    #[serde(default)]
     tree_id: String,
     url: String,
 }
@@ -286,6 +296,8 @@ pub struct HeadCommit {
 pub struct Pusher {
     date: Option<String>,
     email: Option<String>,
+    // gitea sends a user object
+    #[serde(alias = "login")]
     name: String,
     username: Option<String>,
 }
@@ -0,0 +1,187 @@
+//! blue/green deploys: `system_name` is a template unit with a `blue` and a
+//! `green` instance. the idle one is started on the new code and health
+//! checked, then the proxy is switched to it and the old one is stopped
+use std::{
+    fmt::Display,
+    fs, thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{eyre, Context};
+use nix::unistd::User;
+use tracing::instrument;
+
+use crate::{
+    config::{BlueGreenConfig, ColorConfig, Service, Target, Timeouts},
+    process, release,
+    units::{self, Systemd},
+};
+
+/// how long to wait between health checks
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// an instance of the template unit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Blue,
+    Green,
+}
+
+impl Color {
+    pub fn other(self) -> Self {
+        match self {
+            Self::Blue => Self::Green,
+            Self::Green => Self::Blue,
+        }
+    }
+
+    fn config(self, config: &BlueGreenConfig) -> &ColorConfig {
+        match self {
+            Self::Blue => &config.blue,
+            Self::Green => &config.green,
+        }
+    }
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Blue => write!(f, "blue"),
+            Self::Green => write!(f, "green"),
+        }
+    }
+}
+
+/// make sure the blue/green config is usable when shook starts
+pub fn check(target: &Target, config: &BlueGreenConfig) -> color_eyre::Result<()> {
+    instance(&target.system_name, Color::Blue)?;
+    if config.reload.is_some() {
+        units::check(&proxy_systemd(target, config))?;
+    }
+    let paths = [&config.link, &config.blue.upstream, &config.green.upstream];
+    if paths.iter().any(|path| !path.is_absolute()) {
+        return Err(eyre!(
+            "blue_green.link and the upstreams have to be absolute"
+        ));
+    }
+    Ok(())
+}
+
+/// the manager the proxy is reloaded through. proxies are usually system
+/// services, even when the colors are user units
+fn proxy_systemd(target: &Target, config: &BlueGreenConfig) -> Systemd {
+    Systemd::new(
+        target.systemd_backend,
+        config.reload_user_mode,
+        &target.username,
+    )
+}
+
+/// the name of a color's instance of a template unit, e.g. `app@blue.service`
+/// for `app@.service`
+pub fn instance(template: &str, color: Color) -> color_eyre::Result<String> {
+    let (prefix, suffix) = template
+        .split_once("@.")
+        .ok_or_else(|| eyre!("{template} isn't a template unit like app@.service"))?;
+    Ok(format!("{prefix}@{color}.{suffix}"))
+}
+
+/// the color the proxy is sending requests to, if `link` points at one
+pub fn live(config: &BlueGreenConfig) -> Option<Color> {
+    let upstream = fs::read_link(&config.link).ok()?;
+    [Color::Blue, Color::Green]
+        .into_iter()
+        .find(|color| color.config(config).upstream == upstream)
+}
+
+/// switch to the idle color: start it, health check it, point the proxy at
+/// it and stop the live one. a color that fails its health check is stopped
+/// and the live one keeps serving
+#[instrument(skip_all)]
+pub fn deploy(
+    target: &Target,
+    config: &BlueGreenConfig,
+    user: &User,
+    unit: &Service,
+) -> color_eyre::Result<()> {
+    let live = live(config);
+    let idle = live.map_or(Color::Blue, Color::other);
+    let systemd = target.systemd();
+    let proxy_systemd = proxy_systemd(target, config);
+    let idle_unit = instance(&unit.name, idle)?;
+    tracing::info!(
+        "starting {} while {} is live",
+        idle_unit,
+        live.map_or("no color".to_string(), |c| c.to_string())
+    );
+
+    let started = units::apply(
+        target,
+        &Service {
+            name: idle_unit.clone(),
+            ..unit.clone()
+        },
+    )
+    .and_then(|()| health_check(idle.config(config), user, config.health_timeout));
+    if let Err(e) = started {
+        if let Err(e) = units::systemctl(&systemd, "stop", &idle_unit) {
+            tracing::error!("failed to stop {idle_unit}: {e}");
+        }
+        return Err(e).with_context(|| format!("starting {idle_unit}"));
+    }
+
+    let previous = fs::read_link(&config.link).ok();
+    release::replace_symlink(&config.link, &idle.config(config).upstream, None)?;
+    tracing::info!("switched {:?} to {}", config.link, idle);
+    if let Some(proxy) = &config.reload {
+        if let Err(e) = units::systemctl(&proxy_systemd, "reload", proxy) {
+            // the proxy still sends requests to the live color, so the link has
+            // to say so too or the next deploy would stop the wrong one
+            let restored = match &previous {
+                Some(previous) => release::replace_symlink(&config.link, previous, None),
+                None => fs::remove_file(&config.link)
+                    .with_context(|| format!("removing {:?}", config.link)),
+            };
+            if let Err(e) = restored {
+                tracing::error!("failed to switch {:?} back: {e}", config.link);
+            }
+            if let Err(e) = units::systemctl(&systemd, "stop", &idle_unit) {
+                tracing::error!("failed to stop {idle_unit}: {e}");
+            }
+            return Err(e).with_context(|| format!("reloading {proxy}"));
+        }
+    }
+
+    if let Some(live) = live {
+        units::systemctl(&systemd, "stop", &instance(&unit.name, live)?)?;
+    }
+    Ok(())
+}
+
+/// run a color's health check until it succeeds, or fail after `timeout`
+/// seconds (0 means no limit). a check that hangs is killed once the time is up
+#[instrument(skip_all)]
+fn health_check(color: &ColorConfig, user: &User, timeout: u64) -> color_eyre::Result<()> {
+    let deadline = Timeouts::limit(timeout).map(|timeout| Instant::now() + timeout);
+    loop {
+        let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        let checked = process::with_timeout(remaining, || {
+            process::run(
+                process::command_as("sh", user)?
+                    .arg("-c")
+                    .arg(&color.health_check),
+                "health check",
+            )
+        });
+        match checked {
+            Ok(()) => return Ok(()),
+            Err(e) if deadline.is_some_and(|d| Instant::now() >= d) => {
+                return Err(e).context("health check didn't pass in time");
+            }
+            Err(_) => {
+                process::check_stopped()?;
+                thread::sleep(HEALTH_INTERVAL);
+            }
+        }
+    }
+}
@@ -1,10 +1,23 @@
-use std::{fmt::Display, net::SocketAddr, path::PathBuf, str::FromStr};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use argh::FromArgs;
+use color_eyre::eyre::Context;
 use github_webhook_extract::EventDiscriminants;
 use serde::{Deserialize, Serialize};
 
-use crate::error::TcpOrUnixParseError;
+use crate::{
+    error::TcpOrUnixParseError,
+    git::{GitBackend, UpdateStrategy},
+    units::{Systemd, SystemdBackend},
+};
 
 /// shook: a webserver that listens for a webhook on
 /// a github repo, that will automatically restart your
@@ -28,6 +41,8 @@ pub enum Action {
     Init(Init),
     Serve(Serve),
     Daemon(Daemon),
+    Deploy(Deploy),
+    Rollback(Rollback),
     Version(Version),
 }
 
@@ -64,6 +79,9 @@ pub struct Init {
     /// is running under. e.g.: if nginx the user should be www-data
     #[argh(option)]
     pub socket_user: Option<String>,
+    /// permissions of the unix socket in octal, e.g. '660' (defaults to 666)
+    #[argh(option, from_str_fn(parse_socket_mode))]
+    pub socket_mode: Option<u32>,
     /// a command to run before restarting the server service. for example recompiling
     /// an executable.
     #[argh(option)]
@@ -71,6 +89,16 @@ pub struct Init {
     /// name of the .service file for shook
     #[argh(option)]
     pub shook_service_name: Option<String>,
+    /// webhook providers to accept deliveries from: 'github,gitea,bitbucket'
+    #[argh(option, from_str_fn(parse_multiple_providers))]
+    pub providers: Option<Vec<Provider>>,
+    /// generate a .socket unit so systemd owns the listening socket
+    #[argh(switch)]
+    pub socket_activation: bool,
+    /// install shook as a user service of `username` and restart the
+    /// service with `systemctl --user`, so shook doesn't need root
+    #[argh(switch)]
+    pub user_mode: bool,
 }
 
 /// init args without all the options
@@ -85,8 +113,34 @@ pub struct InitConfig {
     pub addr: TcpOrUnix,
     pub socket_group: String,
     pub socket_user: String,
+    #[serde(default = "default_socket_mode")]
+    pub socket_mode: u32,
     pub pre_restart_command: String,
     pub shook_service_name: String,
+    #[serde(default = "default_providers")]
+    pub providers: Vec<Provider>,
+    #[serde(default)]
+    pub socket_activation: bool,
+    #[serde(default)]
+    pub systemd_backend: SystemdBackend,
+    #[serde(default)]
+    pub user_mode: bool,
+}
+
+impl InitConfig {
+    /// name of the .socket unit matching the shook service
+    pub fn shook_socket_name(&self) -> String {
+        let stem = self
+            .shook_service_name
+            .strip_suffix(".service")
+            .unwrap_or(&self.shook_service_name);
+        format!("{stem}.socket")
+    }
+
+    /// the systemd manager shook's own units are installed into
+    pub fn systemd(&self) -> Systemd {
+        Systemd::new(self.systemd_backend, self.user_mode, &self.username)
+    }
 }
 
 /// activate the webhook server - each argument overrides the value in
@@ -123,10 +177,19 @@ pub struct Serve {
     /// is running under. e.g.: if nginx the user should be www-data
     #[argh(option)]
     pub socket_user: Option<String>,
+    /// override permissions of the unix socket in octal, e.g. '660'
+    #[argh(option, from_str_fn(parse_socket_mode))]
+    pub socket_mode: Option<u32>,
     /// a command to run before restarting the server service. for example recompiling
     /// an executable.
     #[argh(option)]
     pub pre_restart_command: Option<String>,
+    /// override webhook providers to accept deliveries from: 'github,gitea,bitbucket'
+    #[argh(option, from_str_fn(parse_multiple_providers))]
+    pub providers: Option<Vec<Provider>>,
+    /// override the path webhooks are received on
+    #[argh(option)]
+    pub path: Option<String>,
 }
 
 /// parse a string like: 'commit,push' into events to listen to
@@ -136,6 +199,20 @@ pub fn parse_multiple_events(s: &str) -> Result<Vec<EventDiscriminants>, String>
         .collect::<Result<Vec<_>, _>>()
 }
 
+/// parse an octal file mode like: '660' or '0o660'
+pub fn parse_socket_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("invalid socket mode: {s}")),
+    }
+}
+
+/// parse a string like: 'github,gitea' into providers to accept
+pub fn parse_multiple_providers(s: &str) -> Result<Vec<Provider>, String> {
+    s.split(',').map(Provider::from_str).collect()
+}
+
 /// speak with the shook daemon
 #[derive(Debug, Clone, FromArgs)]
 #[argh(subcommand, name = "daemon")]
@@ -157,6 +234,46 @@ pub enum DaemonAction {
     Stop(DaemonStop),
 }
 
+/// run the deploy pipeline once, right now
+#[derive(Debug, Clone, FromArgs)]
+#[argh(subcommand, name = "deploy")]
+pub struct Deploy {
+    /// path to the repository holding `shook.toml` (defaults to the current directory)
+    #[argh(option)]
+    pub repo_path: Option<PathBuf>,
+    /// target to deploy (defaults to the top level target)
+    #[argh(option)]
+    pub target: Option<String>,
+    /// commit, branch or tag to deploy, it has to be on the tracked branch
+    /// (defaults to the tip of the tracked branch)
+    #[argh(option, long = "ref")]
+    pub git_ref: Option<String>,
+    /// deploy the commit that's checked out without pulling
+    #[argh(switch)]
+    pub skip_pull: bool,
+    /// only print what each stage would do
+    #[argh(switch)]
+    pub dry_run: bool,
+}
+
+/// redeploy the commit of an earlier deploy
+#[derive(Debug, Clone, FromArgs)]
+#[argh(subcommand, name = "rollback")]
+pub struct Rollback {
+    /// path to the repository holding `shook.toml` (defaults to the current directory)
+    #[argh(option)]
+    pub repo_path: Option<PathBuf>,
+    /// target to roll back (defaults to the top level target)
+    #[argh(option)]
+    pub target: Option<String>,
+    /// commit to roll back to
+    #[argh(option)]
+    pub sha: Option<String>,
+    /// roll back to the commit deployed this many successful deploys ago (defaults to 1)
+    #[argh(option)]
+    pub steps: Option<usize>,
+}
+
 /// print out shook's version
 #[derive(Debug, Clone, FromArgs)]
 #[argh(subcommand, name = "version")]
@@ -180,16 +297,9 @@ pub struct DaemonEnable {}
 /// server configuration parsed from `shook.toml`
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ServerConfig {
-    /// linux user name to run git with
-    pub username: String,
-    /// path to the repository
-    pub repo_path: PathBuf,
-    /// the remote to track for pulling changes
-    pub remote: String,
-    /// the branch to track for pulling changes
-    pub branch: String,
-    /// name of systemd service to update when receiving a github event
-    pub system_name: String,
+    /// the target deployed by webhooks, configured at the top level of `shook.toml`
+    #[serde(flatten)]
+    pub target: Target,
     /// github events to update the server after receiving
     pub update_events: Vec<EventDiscriminants>,
     /// address to serve on: a path to a unix socket, or an ip address for tcp
@@ -200,18 +310,335 @@ pub struct ServerConfig {
     /// override the owner of the unix socket. should be set to the user your server/proxy
     /// is running under. e.g.: if nginx the user should be www-data
     pub socket_user: String,
+    /// permissions of the unix socket, e.g. `0o660`
+    #[serde(default = "default_socket_mode")]
+    pub socket_mode: u32,
+    /// webhook providers to accept deliveries from
+    #[serde(default = "default_providers")]
+    pub providers: Vec<Provider>,
+    /// additional deployment targets, each under a `[targets.<name>]` table
+    #[serde(default)]
+    pub targets: BTreeMap<String, Target>,
+    /// authentication for the deploy hook used by custom ci systems, the hook
+    /// is disabled if this is missing
+    pub deploy_hook: Option<DeployHookConfig>,
+    /// serve https instead of http (only applicable if serving over tcp)
+    pub tls: Option<TlsConfig>,
+    /// more addresses to serve on, each under a `[[listen]]` table
+    #[serde(default)]
+    pub listen: Vec<ListenConfig>,
+    /// how long each stage of a deploy can take
+    #[serde(default)]
+    pub timeouts: Timeouts,
+}
+
+/// seconds each stage of a deploy can run for before its commands are killed,
+/// 0 for no limit
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Timeouts {
+    /// fetching and checking out the commit
+    pub pull: u64,
+    /// running the steps before or after restarting
+    pub build: u64,
+    /// restarting the services
+    pub restart: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            pull: 300,
+            build: 3600,
+            restart: 300,
+        }
+    }
+}
+
+impl Timeouts {
+    /// a timeout in seconds as a duration, `None` if there's no limit
+    pub fn limit(secs: u64) -> Option<Duration> {
+        (secs != 0).then(|| Duration::from_secs(secs))
+    }
+}
+
+/// an address to serve on
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ListenConfig {
+    /// a path to a unix socket, or an ip address for tcp
+    pub addr: TcpOrUnix,
+    /// the owner of the unix socket, left unchanged if missing
+    pub socket_user: Option<String>,
+    /// the group of the unix socket, left unchanged if missing
+    pub socket_group: Option<String>,
+    /// permissions of the unix socket, e.g. `0o660`
+    #[serde(default = "default_socket_mode")]
+    pub socket_mode: u32,
+}
+
+fn default_socket_mode() -> u32 {
+    0o666
+}
+
+/// a repository and the service it's deployed to
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Target {
+    /// linux user name to run git with
+    pub username: String,
+    /// path to the repository
+    pub repo_path: PathBuf,
+    /// the remote to track for pulling changes
+    pub remote: String,
+    /// the branch to track for pulling changes
+    pub branch: String,
+    /// name of systemd service to update when receiving a github event
+    pub system_name: String,
+    /// globs of files that have to change for `system_name` to be restarted, it's
+    /// restarted on every deploy if empty
+    #[serde(default)]
+    pub restart_paths: Vec<String>,
+    /// what to do with `system_name` after updating
+    #[serde(default)]
+    pub system_action: UnitAction,
+    /// wait for `system_name` to be active before continuing
+    #[serde(default)]
+    pub wait_active: bool,
+    /// how services are restarted: `systemctl` runs the executable, `dbus` calls
+    /// systemd directly
+    #[serde(default)]
+    pub systemd_backend: SystemdBackend,
+    /// control `username`'s own systemd manager (`systemctl --user`) instead of
+    /// the system one
+    #[serde(default)]
+    pub user_mode: bool,
+    /// more systemd services restarted after `system_name` in the order they're
+    /// listed, each under a `[[services]]` table
+    #[serde(default)]
+    pub services: Vec<Service>,
     /// a command to run before restarting the server service. for example recompiling
-    /// an executable.
+    /// an executable. runs before any `[[steps]]`, left out if empty
+    #[serde(default)]
     pub pre_restart_command: String,
+    /// named commands run before or after restarting the service, each under a
+    /// `[[steps]]` table
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    /// path webhooks for this target are posted to. the top level target defaults to `/`,
+    /// other targets don't receive webhooks without one
+    pub path: Option<String>,
+    /// how to update the repository: `cli` runs `git pull` as `username`, `git2` uses
+    /// libgit2 inside shook
+    #[serde(default)]
+    pub git_backend: GitBackend,
+    /// how the pushed commit is applied: `merge` like `git pull`, `ff-only`, or
+    /// `reset-hard` which saves local changes to a backup ref and handles force pushes
+    #[serde(default)]
+    pub update_strategy: UpdateStrategy,
+    /// sync and update submodules recursively after updating
+    #[serde(default)]
+    pub submodules: bool,
+    /// fetch and check out git lfs files after updating, needs `git-lfs` installed
+    #[serde(default)]
+    pub lfs: bool,
+    /// check out each deployed commit to its own release directory instead of
+    /// deploying `repo_path` directly
+    pub releases: Option<ReleasesConfig>,
+    /// deploy `system_name`, a template unit, by switching between a blue and a
+    /// green instance instead of restarting it
+    pub blue_green: Option<BlueGreenConfig>,
+}
+
+/// a command in a target's deploy pipeline
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Step {
+    /// name of the step in logs
+    pub name: String,
+    /// shell command to run
+    pub command: String,
+    /// whether to run before or after restarting the service
+    #[serde(default)]
+    pub stage: Stage,
+    /// directory to run in, relative to the checkout being deployed
+    pub dir: Option<PathBuf>,
+    /// extra environment variables
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// seconds the command can run for before it's killed
+    pub timeout: Option<u64>,
+    /// keep deploying if the command fails. a deploy that times out or is
+    /// cancelled during the step still stops
+    #[serde(default)]
+    pub continue_on_error: bool,
+    /// linux user name to run the command as, defaults to the target's `username`
+    pub user: Option<String>,
+    /// globs of files that have to change for the step to run, it runs on every
+    /// deploy if empty
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+/// a systemd service restarted by a deploy
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Service {
+    /// name of the systemd unit
+    pub name: String,
+    /// globs of files that have to change for the service to be restarted, it's
+    /// restarted on every deploy if empty
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// what to do with the service
+    #[serde(default)]
+    pub action: UnitAction,
+    /// wait for the service to be active before continuing
+    #[serde(default)]
+    pub wait_active: bool,
+}
+
+/// how a deploy applies to a systemd service, named like the `systemctl` commands
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnitAction {
+    #[default]
+    Restart,
+    /// reload the service's configuration, failing if it can't be reloaded
+    Reload,
+    /// reload if the service supports it, otherwise restart
+    ReloadOrRestart,
+    /// restart the service only if it's running
+    TryRestart,
+}
+
+impl UnitAction {
+    /// the `systemctl` command for the action
+    pub fn command(self) -> &'static str {
+        match self {
+            Self::Restart => "restart",
+            Self::Reload => "reload",
+            Self::ReloadOrRestart => "reload-or-restart",
+            Self::TryRestart => "try-restart",
+        }
+    }
+}
+
+/// when a step runs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// after updating the checkout, before restarting. with releases these
+    /// only run when a release is first checked out
+    #[default]
+    PreRestart,
+    /// after restarting the service
+    PostRestart,
+}
+
+/// where releases are checked out and how many are kept
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReleasesConfig {
+    /// directory holding `releases/<sha>` and the `current` symlink
+    pub path: PathBuf,
+    /// number of releases to keep for rolling back, including the live one
+    #[serde(default = "default_keep_releases")]
+    pub keep: usize,
+}
+
+fn default_keep_releases() -> usize {
+    5
+}
+
+/// how to switch the proxy between the two instances of a blue/green deploy
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BlueGreenConfig {
+    /// symlink read by the proxy, pointed at the live color's `upstream`
+    pub link: PathBuf,
+    /// unit to reload after switching `link`, e.g. `nginx.service`
+    pub reload: Option<String>,
+    /// reload `reload` through the user manager of `username` instead of the
+    /// system manager
+    #[serde(default)]
+    pub reload_user_mode: bool,
+    /// seconds the new color has to pass its health check in, 0 means no limit
+    #[serde(default = "default_health_timeout")]
+    pub health_timeout: u64,
+    pub blue: ColorConfig,
+    pub green: ColorConfig,
+}
+
+/// one instance of a blue/green deploy
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ColorConfig {
+    /// file the proxy reads to send requests to this color
+    pub upstream: PathBuf,
+    /// shell command that succeeds once this color is serving, e.g.
+    /// `curl -fsS http://127.0.0.1:8001/health`
+    pub health_check: String,
+}
+
+fn default_health_timeout() -> u64 {
+    60
+}
+
+/// credentials accepted by the deploy hook. a request is accepted if it
+/// matches either of them
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeployHookConfig {
+    /// token expected in an `Authorization: Bearer <token>` header
+    pub token: Option<String>,
+    /// secret used to check the `X-Shook-Signature: sha256=<hmac>` header
+    pub secret: Option<String>,
+    /// path the deploy hook is served on
+    #[serde(default = "default_deploy_hook_path")]
+    pub path: String,
+}
+
+/// certificate and key used to terminate tls, reloaded when shook receives SIGHUP
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// path to the pem encoded certificate chain
+    pub cert: PathBuf,
+    /// path to the pem encoded private key
+    pub key: PathBuf,
+}
+
+fn default_deploy_hook_path() -> String {
+    "/deploy".to_string()
+}
+
+impl Target {
+    /// the systemd manager the target's services belong to
+    pub fn systemd(&self) -> Systemd {
+        Systemd::new(self.systemd_backend, self.user_mode, &self.username)
+    }
+
+    /// the systemd services a deploy applies to in order, `system_name` first
+    pub fn units(&self) -> Vec<Service> {
+        let system = Service {
+            name: self.system_name.clone(),
+            paths: self.restart_paths.clone(),
+            action: self.system_action,
+            wait_active: self.wait_active,
+        };
+        [system]
+            .into_iter()
+            .chain(self.services.iter().cloned())
+            .collect()
+    }
 }
 
 impl ServerConfig {
+    /// read `shook.toml` from a repository, the current directory by default
+    pub fn load(repo_path: Option<&Path>) -> color_eyre::Result<Self> {
+        let config_path = repo_path.unwrap_or(Path::new(".")).join("shook.toml");
+        let buf = fs::read_to_string(config_path).context("reading shook config")?;
+        toml::from_str(&buf).context("parsing shook config")
+    }
+
     pub fn merge(&mut self, cli: Serve) {
         if let Some(p) = cli.repo_path {
-            self.repo_path = p;
+            self.target.repo_path = p;
         }
         if let Some(n) = cli.system_name {
-            self.system_name = n;
+            self.target.system_name = n;
         }
         if let Some(e) = cli.update_events {
             self.update_events = e;
@@ -220,13 +647,13 @@ impl ServerConfig {
             self.addr = a;
         }
         if let Some(u) = cli.username {
-            self.username = u;
+            self.target.username = u;
         }
         if let Some(r) = cli.remote {
-            self.remote = r;
+            self.target.remote = r;
         }
         if let Some(b) = cli.branch {
-            self.branch = b;
+            self.target.branch = b;
         }
         if let Some(g) = cli.socket_group {
             self.socket_group = g;
@@ -234,10 +661,94 @@ impl ServerConfig {
         if let Some(u) = cli.socket_user {
             self.socket_user = u;
         }
+        if let Some(m) = cli.socket_mode {
+            self.socket_mode = m;
+        }
         if let Some(c) = cli.pre_restart_command {
-            self.pre_restart_command = c;
+            self.target.pre_restart_command = c;
+        }
+        if let Some(p) = cli.providers {
+            self.providers = p;
         }
+        if let Some(p) = cli.path {
+            self.target.path = Some(p);
+        }
+    }
+
+    /// every address to serve on, starting with `addr`
+    pub fn listen_addrs(&self) -> Vec<ListenConfig> {
+        let non_empty = |s: &String| (!s.is_empty()).then(|| s.clone());
+        let primary = ListenConfig {
+            addr: self.addr.clone(),
+            socket_user: non_empty(&self.socket_user),
+            socket_group: non_empty(&self.socket_group),
+            socket_mode: self.socket_mode,
+        };
+        std::iter::once(primary)
+            .chain(self.listen.iter().cloned())
+            .collect()
     }
+
+    /// paths receiving webhooks along with the name of the target they deploy,
+    /// `None` being the top level target
+    pub fn webhook_paths(&self) -> Vec<(Option<&str>, &str)> {
+        let top_level = (None, self.target.path.as_deref().unwrap_or("/"));
+        std::iter::once(top_level)
+            .chain(self.targets.iter().filter_map(|(name, target)| {
+                target
+                    .path
+                    .as_deref()
+                    .map(|path| (Some(name.as_str()), path))
+            }))
+            .collect()
+    }
+
+    /// every target along with its name, `None` being the top level target
+    pub fn all_targets(&self) -> impl Iterator<Item = (Option<&str>, &Target)> {
+        std::iter::once((None, &self.target)).chain(
+            self.targets
+                .iter()
+                .map(|(name, target)| (Some(name.as_str()), target)),
+        )
+    }
+
+    /// get a target by name, `None` gets the top level target
+    pub fn target(&self, name: Option<&str>) -> Option<&Target> {
+        match name {
+            Some(name) => self.targets.get(name),
+            None => Some(&self.target),
+        }
+    }
+}
+
+/// a service that sends webhooks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    Github,
+    /// gitea and forgejo
+    Gitea,
+    /// bitbucket cloud and bitbucket server
+    Bitbucket,
+}
+
+impl FromStr for Provider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "github" => Ok(Self::Github),
+            "gitea" | "forgejo" => Ok(Self::Gitea),
+            "bitbucket" => Ok(Self::Bitbucket),
+            _ => Err(format!("unknown provider: {s}")),
+        }
+    }
+}
+
+/// gitea and bitbucket send unsigned deliveries unless a secret is set, so they
+/// have to be enabled explicitly
+pub fn default_providers() -> Vec<Provider> {
+    vec![Provider::Github]
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -1,8 +1,11 @@
-use std::{fs::File, io::Read, process::Command};
+use std::{fs::File, io::Read};
 
-use color_eyre::eyre::{eyre, Context};
+use color_eyre::eyre::Context;
 
-use crate::config::{Daemon, DaemonAction, InitConfig};
+use crate::{
+    config::{Daemon, DaemonAction, InitConfig},
+    units,
+};
 
 /// send command to the shook systemd service
 pub fn daemon_message(args: Daemon) -> color_eyre::Result<()> {
@@ -14,23 +17,36 @@ pub fn daemon_message(args: Daemon) -> color_eyre::Result<()> {
     file.read_to_string(&mut buf)
         .context("reading shook config")?;
     let config: InitConfig = toml::from_str(&buf).context("parsing shook config")?;
-    let service_name = config.shook_service_name;
+    let service_name = &config.shook_service_name;
+    let systemd = config.systemd();
+    // the socket has to be listening before the service starts and is stopped
+    // after it, so it isn't reactivated
+    let socket_name = config.socket_activation.then(|| config.shook_socket_name());
 
     match args.action {
         DaemonAction::Start(_) => {
             tracing::info!("starting daemon");
 
-            run_systemctl_command("start", &service_name)?;
+            if let Some(socket_name) = &socket_name {
+                units::systemctl(&systemd, "start", socket_name)?;
+            }
+            units::systemctl(&systemd, "start", service_name)?;
         }
         DaemonAction::Stop(_) => {
             tracing::info!("stopping daemon");
 
-            run_systemctl_command("stop", &service_name)?;
+            units::systemctl(&systemd, "stop", service_name)?;
+            if let Some(socket_name) = &socket_name {
+                units::systemctl(&systemd, "stop", socket_name)?;
+            }
         }
         DaemonAction::Enable(_) => {
             tracing::info!("enabling daemon");
 
-            run_systemctl_command("enable", &service_name)?;
+            if let Some(socket_name) = &socket_name {
+                units::systemctl(&systemd, "enable", socket_name)?;
+            }
+            units::systemctl(&systemd, "enable", service_name)?;
         }
     };
 
@@ -38,28 +54,3 @@ pub fn daemon_message(args: Daemon) -> color_eyre::Result<()> {
 
     Ok(())
 }
-
-/// run a systemctl command, pipe the output and return if it doesn't exit with 0
-fn run_systemctl_command(name: &str, service_name: &str) -> color_eyre::Result<()> {
-    let mut handle = Command::new("systemctl")
-        .arg(name)
-        .arg(service_name)
-        .spawn()
-        .context("spawning systemctl")?;
-
-    let status = handle.wait()?;
-
-    tracing::info!(
-        "systemctl returned status: {}",
-        status
-            .code()
-            .map(|s| s.to_string())
-            .unwrap_or("<terminated by signal>".to_string())
-    );
-
-    if status.code().unwrap_or(1) != 0 {
-        return Err(eyre!("systemctl returned error"));
-    }
-
-    Ok(())
-}
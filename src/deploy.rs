@@ -0,0 +1,75 @@
+use color_eyre::eyre::eyre;
+use uuid::Uuid;
+
+use crate::{
+    config::{Deploy, ServerConfig, Target},
+    git,
+    server::{self, DeployKind, DeployRequest},
+    user,
+};
+
+/// run the deploy pipeline in the terminal, the way `serve` runs it for a webhook
+pub fn deploy(args: Deploy) -> color_eyre::Result<()> {
+    let config = ServerConfig::load(args.repo_path.as_deref())?;
+    let name = args.target.as_deref();
+    let target = config
+        .target(name)
+        .ok_or_else(|| eyre!("unknown target: {}", name.unwrap_or_default()))?;
+
+    let (kind, sha) = match (args.git_ref, args.skip_pull) {
+        (Some(_), true) => return Err(eyre!("--ref and --skip-pull can't be used together")),
+        (None, true) => (DeployKind::CheckedOut, None),
+        (None, false) => (DeployKind::Update, None),
+        (Some(r#ref), false) => (DeployKind::Update, resolve(target, &r#ref, args.dry_run)?),
+    };
+
+    server::deploy(
+        target,
+        &config.timeouts,
+        &DeployRequest {
+            guid: Uuid::new_v4(),
+            target: args.target.clone(),
+            kind,
+            sha,
+            forced: false,
+            dry_run: args.dry_run,
+        },
+    )
+    .map_err(|_| eyre!("deploy failed"))
+}
+
+/// the commit `--ref` names. `None` is the tip of the tracked branch. only
+/// commits on the tracked branch are deployed, so other commits are refused
+/// before the deploy starts. checking that fetches the branch, which a dry run
+/// skips
+fn resolve(target: &Target, r#ref: &str, dry_run: bool) -> color_eyre::Result<Option<String>> {
+    if r#ref == target.branch || r#ref == format!("refs/heads/{}", target.branch) {
+        return Ok(None);
+    }
+
+    let user = user::user(&target.username)?;
+    let sha = match r#ref.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => r#ref.to_string(),
+        false => {
+            let sha = git::resolve_ref(target, &user, r#ref)?;
+            tracing::info!("{} is {}", r#ref, sha);
+            sha
+        }
+    };
+    if dry_run {
+        tracing::info!(
+            "would fetch {} {} and check {} is on it",
+            target.remote,
+            target.branch,
+            sha
+        );
+    } else if !git::on_branch(target, &user, &sha)? {
+        return Err(eyre!(
+            "{} isn't on {} {}, only commits of the tracked branch can be deployed",
+            r#ref,
+            target.remote,
+            target.branch
+        ));
+    }
+    Ok(Some(sha))
+}
@@ -0,0 +1,85 @@
+//! Deploy hook for custom ci systems (jenkins, drone, ...) that want shook to
+//! deploy a commit after their own checks pass. The body is json:
+//!
+//! ```json
+//! { "ref": "refs/heads/main", "sha": "9f0b...", "target": "api" }
+//! ```
+//!
+//! `ref` and `target` are optional. Requests are authenticated with either an
+//! `Authorization: Bearer <token>` header or an `X-Shook-Signature: sha256=<hmac>`
+//! header holding the hex hmac of the body.
+use axum::http::{HeaderMap, StatusCode};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::instrument;
+
+use crate::config::DeployHookConfig;
+
+/// body of a deploy hook request
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DeployHookPayload {
+    /// ref that was tested, `refs/heads/main` or `main`. must be the target's branch
+    pub r#ref: Option<String>,
+    /// commit to deploy
+    pub sha: String,
+    /// target to deploy, the top level target in `shook.toml` if missing
+    pub target: Option<String>,
+}
+
+impl DeployHookPayload {
+    /// check if `ref` (if given) names `branch`
+    pub fn matches_branch(&self, branch: &str) -> bool {
+        match &self.r#ref {
+            Some(r) => r.strip_prefix("refs/heads/").unwrap_or(r) == branch,
+            None => true,
+        }
+    }
+}
+
+/// authenticate and parse a deploy hook request
+#[instrument(skip_all)]
+pub fn verify(
+    config: &DeployHookConfig,
+    headers: &HeaderMap,
+    bytes: &[u8],
+) -> Result<DeployHookPayload, StatusCode> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let bearer = header("Authorization").and_then(|v| v.strip_prefix("Bearer "));
+    let signature = header("X-Shook-Signature").map(|v| v.to_lowercase());
+    let verified = match (bearer, &signature, &config.token, &config.secret) {
+        (Some(bearer), _, Some(token), _) => {
+            tracing::debug!("using bearer token");
+            constant_time_eq(bearer.as_bytes(), token.as_bytes())
+        }
+        (_, Some(signature), _, Some(secret)) => {
+            tracing::debug!("using sha256");
+            let signature = signature
+                .split_once('=')
+                .and_then(|(_, s)| hex::decode(s).ok())
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|e| {
+                tracing::error!("error creating hmac: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            mac.update(bytes);
+            mac.verify_slice(&signature).is_ok()
+        }
+        _ => false,
+    };
+    if !verified {
+        tracing::warn!("deploy hook request not verified");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    serde_json::from_slice(bytes).map_err(|e| {
+        tracing::warn!("failed to deserialize deploy hook body: {}", e);
+        StatusCode::BAD_REQUEST
+    })
+}
+
+/// compare secrets without leaking where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -0,0 +1,1262 @@
+//! updating a target's checkout, either with the git executable or with
+//! libgit2 when shook is built with the `git2` feature
+use std::{
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::eyre;
+use nix::unistd::{Uid, User};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{config::Target, process};
+
+/// how a target's repository is updated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GitBackend {
+    /// run `git` as the target's user
+    #[default]
+    Cli,
+    /// use libgit2 inside shook
+    Git2,
+}
+
+/// how the fetched commit is applied to the checkout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateStrategy {
+    /// fast-forward, failing if the checkout has local commits or the branch was
+    /// force pushed
+    FfOnly,
+    /// reset to the fetched commit, saving local changes and commits to a
+    /// `refs/shook/backup/<time>` ref first
+    ResetHard,
+    /// merge like `git pull`, failing if the branch was force pushed
+    #[default]
+    Merge,
+}
+
+/// how the checkout changed after updating
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Update {
+    /// commit checked out before updating
+    pub old_head: String,
+    /// commit checked out after updating
+    pub new_head: String,
+    /// paths changed between the two commits, relative to the repository. a
+    /// moved file is listed under its old and new path
+    pub changed_files: Vec<String>,
+}
+
+/// make sure a target's backend can be used, so misconfigurations are found
+/// when shook starts instead of on the first deploy
+pub fn check(target: &Target, user: &User) -> color_eyre::Result<()> {
+    match target.git_backend {
+        GitBackend::Cli => Ok(()),
+        // libgit2 runs inside shook, so it can't switch users like the git executable
+        GitBackend::Git2 if user.uid != Uid::current() => Err(eyre!(
+            "the git2 backend only updates repositories as shook's own user, run shook as {} or use the cli backend",
+            user.name
+        )),
+        GitBackend::Git2 if cfg!(feature = "git2") => Ok(()),
+        GitBackend::Git2 => Err(eyre!(
+            "the git2 backend needs shook to be built with the git2 feature"
+        )),
+    }
+}
+
+/// update a target's checkout from its remote branch. `sha` is the commit
+/// to deploy, if it's known, otherwise the tip of the branch is deployed.
+/// `forced` is set if the webhook reported a force push, which is also
+/// detected from the fetched branch
+#[instrument(skip_all)]
+pub fn update(
+    target: &Target,
+    user: &User,
+    sha: Option<&str>,
+    forced: bool,
+) -> color_eyre::Result<Update> {
+    // arguments starting with '-' would be parsed as options by git
+    if target.remote.starts_with('-') || target.branch.starts_with('-') {
+        return Err(eyre!("remote and branch can't start with '-'"));
+    }
+    if let Some(sha) = sha {
+        check_sha(sha)?;
+    }
+    check(target, user)?;
+    tracing::debug!(
+        ?sha,
+        forced,
+        strategy = ?target.update_strategy,
+        "updating {:?}",
+        target.repo_path
+    );
+
+    let update = match target.git_backend {
+        GitBackend::Cli => cli::update(target, user, sha, forced)?,
+        #[cfg(feature = "git2")]
+        GitBackend::Git2 => native::update(target, sha, forced)?,
+        #[cfg(not(feature = "git2"))]
+        GitBackend::Git2 => unreachable!("rejected by check"),
+    };
+
+    checkout_extras(target, user, &target.repo_path)?;
+
+    Ok(update)
+}
+
+/// fetch the target's branch without touching the checkout, and return the
+/// commit to deploy: `sha` if it's on the branch, or the tip of the branch.
+/// used for release directories, where `repo_path` is only fetched into
+#[instrument(skip_all)]
+pub fn fetch(target: &Target, user: &User, sha: Option<&str>) -> color_eyre::Result<String> {
+    if target.remote.starts_with('-') || target.branch.starts_with('-') {
+        return Err(eyre!("remote and branch can't start with '-'"));
+    }
+    if let Some(sha) = sha {
+        check_sha(sha)?;
+    }
+    check(target, user)?;
+
+    match target.git_backend {
+        GitBackend::Cli => cli::fetch(target, user, sha),
+        #[cfg(feature = "git2")]
+        GitBackend::Git2 => native::fetch_commit(target, sha),
+        #[cfg(not(feature = "git2"))]
+        GitBackend::Git2 => unreachable!("rejected by check"),
+    }
+}
+
+/// check if `sha` is on the target's remote branch, fetching the branch
+/// without moving the ref it's usually fetched into, so force pushes are
+/// still noticed by the next deploy
+#[instrument(skip_all)]
+pub fn on_branch(target: &Target, user: &User, sha: &str) -> color_eyre::Result<bool> {
+    if target.remote.starts_with('-') || target.branch.starts_with('-') {
+        return Err(eyre!("remote and branch can't start with '-'"));
+    }
+    check_sha(sha)?;
+    check(target, user)?;
+
+    match target.git_backend {
+        GitBackend::Cli => cli::on_branch(target, user, sha),
+        #[cfg(feature = "git2")]
+        GitBackend::Git2 => native::on_branch(target, sha),
+        #[cfg(not(feature = "git2"))]
+        GitBackend::Git2 => unreachable!("rejected by check"),
+    }
+}
+
+/// the full id of a commit that's already in the target's repository
+pub fn resolve_commit(target: &Target, user: &User, sha: &str) -> color_eyre::Result<String> {
+    check_sha(sha)?;
+    check(target, user)?;
+
+    match target.git_backend {
+        GitBackend::Cli => cli::rev_parse(target, user, sha),
+        #[cfg(feature = "git2")]
+        GitBackend::Git2 => native::resolve_commit(target, sha),
+        #[cfg(not(feature = "git2"))]
+        GitBackend::Git2 => unreachable!("rejected by check"),
+    }
+}
+
+/// move the target's checkout to `sha`, an earlier deploy that's already in
+/// the repository, e.g. to roll back
+#[instrument(skip_all)]
+pub fn checkout(target: &Target, user: &User, sha: &str) -> color_eyre::Result<Update> {
+    check_sha(sha)?;
+    check(target, user)?;
+
+    let update = match target.git_backend {
+        GitBackend::Cli => cli::checkout(target, user, sha)?,
+        #[cfg(feature = "git2")]
+        GitBackend::Git2 => native::checkout(target, sha)?,
+        #[cfg(not(feature = "git2"))]
+        GitBackend::Git2 => unreachable!("rejected by check"),
+    };
+    checkout_extras(target, user, &target.repo_path)?;
+
+    Ok(update)
+}
+
+/// the commit checked out in the target's repository, as an update that
+/// didn't change anything
+pub fn current(target: &Target, user: &User) -> color_eyre::Result<Update> {
+    check(target, user)?;
+    let head = match target.git_backend {
+        GitBackend::Cli => cli::head(target, user)?,
+        #[cfg(feature = "git2")]
+        GitBackend::Git2 => native::head(target)?,
+        #[cfg(not(feature = "git2"))]
+        GitBackend::Git2 => unreachable!("rejected by check"),
+    };
+
+    Ok(Update {
+        old_head: head.clone(),
+        new_head: head,
+        changed_files: Vec::new(),
+    })
+}
+
+/// paths changed between two commits of the target's repository
+pub fn changed_files(
+    target: &Target,
+    user: &User,
+    old: &str,
+    new: &str,
+) -> color_eyre::Result<Vec<String>> {
+    check_sha(old)?;
+    check_sha(new)?;
+    check(target, user)?;
+
+    match target.git_backend {
+        GitBackend::Cli => cli::changed_files(target, user, old, new),
+        #[cfg(feature = "git2")]
+        GitBackend::Git2 => native::changed_files(target, old, new),
+        #[cfg(not(feature = "git2"))]
+        GitBackend::Git2 => unreachable!("rejected by check"),
+    }
+}
+
+/// find the commit a branch or tag (e.g. `v1.2`, `refs/heads/main`) on the
+/// target's remote points at
+#[instrument(skip_all)]
+pub fn resolve_ref(target: &Target, user: &User, r#ref: &str) -> color_eyre::Result<String> {
+    if r#ref.starts_with('-') || target.remote.starts_with('-') {
+        return Err(eyre!("remote and ref can't start with '-'"));
+    }
+    check(target, user)?;
+
+    match target.git_backend {
+        GitBackend::Cli => cli::resolve_ref(target, user, r#ref),
+        #[cfg(feature = "git2")]
+        GitBackend::Git2 => native::resolve_ref(target, r#ref),
+        #[cfg(not(feature = "git2"))]
+        GitBackend::Git2 => unreachable!("rejected by check"),
+    }
+}
+
+/// pick the commit `ref` names out of the refs listed by a remote, matching
+/// the end of the ref names like `git ls-remote` does
+fn pick_ref(refs: Vec<(String, String)>, r#ref: &str) -> color_eyre::Result<String> {
+    let matches = |name: &str| name == r#ref || name.ends_with(&format!("/{ref}"));
+    // annotated tags are listed again with `^{}` for the commit they point at
+    if let Some((sha, _)) = refs
+        .iter()
+        .find(|(_, name)| name.strip_suffix("^{}").is_some_and(matches))
+    {
+        return Ok(sha.clone());
+    }
+
+    let mut found = refs.into_iter().filter(|(_, name)| matches(name));
+    match (found.next(), found.next()) {
+        (Some((sha, _)), None) => Ok(sha),
+        (Some((_, first)), Some((_, second))) => {
+            Err(eyre!("{ref} is ambiguous, it matches {first} and {second}"))
+        }
+        (None, _) => Err(eyre!("{ref} wasn't found on the remote")),
+    }
+}
+
+/// update the submodules and lfs files of a checkout of the target, if they're
+/// enabled. libgit2 doesn't update submodules recursively or run the lfs
+/// filters, so both backends use the git executable for them
+pub fn checkout_extras(target: &Target, user: &User, dir: &Path) -> color_eyre::Result<()> {
+    if target.submodules {
+        update_submodules(dir, user)?;
+    }
+    if target.lfs {
+        checkout_lfs(target, dir, user)?;
+    }
+    Ok(())
+}
+
+/// add a worktree of the target's repository at `dir`, with `sha` checked out
+#[instrument(skip_all)]
+pub fn add_worktree(target: &Target, user: &User, dir: &Path, sha: &str) -> color_eyre::Result<()> {
+    tracing::info!("checking out {} to {:?}", sha, dir);
+    process::run(
+        git(&target.repo_path, user)?
+            .args(["worktree", "add", "--detach"])
+            .arg(dir)
+            .arg(sha),
+        "git worktree add",
+    )
+}
+
+/// remove a worktree of the target's repository, along with any changes in it
+#[instrument(skip_all)]
+pub fn remove_worktree(target: &Target, user: &User, dir: &Path) -> color_eyre::Result<()> {
+    tracing::info!("removing {:?}", dir);
+    // twice, so a worktree left locked by an interrupted `git worktree add` is
+    // removed too
+    process::run(
+        git(&target.repo_path, user)?
+            .args(["worktree", "remove", "--force", "--force"])
+            .arg(dir),
+        "git worktree remove",
+    )
+}
+
+/// forget worktrees whose directories were deleted
+pub fn prune_worktrees(target: &Target, user: &User) -> color_eyre::Result<()> {
+    process::run(
+        git(&target.repo_path, user)?.args(["worktree", "prune"]),
+        "git worktree prune",
+    )
+}
+
+/// sync submodule urls from `.gitmodules` and check out the commits recorded
+/// in the superproject
+#[instrument(skip_all)]
+fn update_submodules(dir: &Path, user: &User) -> color_eyre::Result<()> {
+    tracing::info!("updating submodules");
+    process::run(
+        git(dir, user)?.args(["submodule", "sync", "--recursive"]),
+        "git submodule sync",
+    )?;
+    process::run(
+        git(dir, user)?.args(["submodule", "update", "--init", "--recursive"]),
+        "git submodule update",
+    )
+}
+
+/// download the lfs objects of the checked out commit and replace the pointer
+/// files with them
+#[instrument(skip_all)]
+fn checkout_lfs(target: &Target, dir: &Path, user: &User) -> color_eyre::Result<()> {
+    tracing::info!("checking out lfs files");
+    process::run(
+        git(dir, user)?
+            .args(["lfs", "fetch"])
+            .arg(&target.remote)
+            .arg("HEAD"),
+        "git lfs fetch",
+    )?;
+    process::run(
+        git(dir, user)?.args(["lfs", "checkout"]),
+        "git lfs checkout",
+    )
+}
+
+/// git running as `user` in a checkout, never prompting for credentials
+fn git(dir: &Path, user: &User) -> color_eyre::Result<Command> {
+    let mut command = process::command_as("git", user)?;
+    command.current_dir(dir).env("GIT_TERMINAL_PROMPT", "0");
+    Ok(command)
+}
+/// make sure a commit is a (possibly abbreviated) hash, so it can't be parsed
+/// as an option or a ref by git
+fn check_sha(sha: &str) -> color_eyre::Result<()> {
+    if sha.len() < 4 || !sha.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(eyre!("invalid commit: {sha}"));
+    }
+    Ok(())
+}
+
+/// refuse to apply a force pushed branch with a strategy that can't handle it
+fn check_forced(target: &Target, forced: bool) -> color_eyre::Result<()> {
+    match target.update_strategy {
+        UpdateStrategy::ResetHard if forced => {
+            tracing::warn!("{} was force pushed, resetting", target.branch);
+            Ok(())
+        }
+        _ if forced => Err(eyre!(
+            "{} was force pushed, which can only be deployed with the reset-hard strategy",
+            target.branch
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// name of a new ref to save local changes to before resetting
+fn backup_ref() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("refs/shook/backup/{}", now.as_secs())
+}
+
+/// make sure `wanted` ended up checked out. HEAD may also be a merge of it with
+/// local commits, or a newer commit of the branch that was already deployed
+fn check_head(wanted: &str, head: &str, contains_wanted: bool) -> color_eyre::Result<()> {
+    if head.starts_with(&wanted.to_lowercase()) {
+        Ok(())
+    } else if contains_wanted {
+        tracing::info!("HEAD is {head}, which already contains {wanted}");
+        Ok(())
+    } else {
+        Err(eyre!("HEAD is {head} after updating, expected {wanted}"))
+    }
+}
+
+/// ref the target's branch is fetched into. `remote` can be a url, which
+/// can't be part of a ref name like `refs/remotes/<remote>/<branch>`
+fn tracking_ref(target: &Target) -> String {
+    format!("refs/shook/tracking/{}", target.branch)
+}
+
+mod cli {
+    use super::*;
+
+    /// fetch the target's branch and apply `sha` (or the fetched branch) with
+    /// the target's strategy, then ask git what changed
+    pub fn update(
+        target: &Target,
+        user: &User,
+        sha: Option<&str>,
+        forced: bool,
+    ) -> color_eyre::Result<Update> {
+        let old_head = rev_parse(target, user, "HEAD")?;
+        let (old_tracking, fetched) = fetch_branch(target, user)?;
+        let wanted = sha.unwrap_or(&fetched);
+        check_on_branch(target, user, wanted, &fetched)?;
+        let old_tracking = old_tracking.trim();
+        let forced = forced
+            || !old_tracking.is_empty() && !is_ancestor(target, user, old_tracking, &fetched)?;
+        check_forced(target, forced)?;
+
+        tracing::info!("applying {} with {:?}", wanted, target.update_strategy);
+        match target.update_strategy {
+            UpdateStrategy::FfOnly => process::run(
+                git(&target.repo_path, user)?
+                    .args(["merge", "--ff-only"])
+                    .arg(wanted),
+                "git merge",
+            )?,
+            UpdateStrategy::Merge => process::run(
+                git(&target.repo_path, user)?
+                    .args(["merge", "--no-edit"])
+                    .arg(wanted),
+                "git merge",
+            )?,
+            UpdateStrategy::ResetHard => {
+                // a newer commit of the branch is already deployed, only discard
+                // local changes
+                let reset_to = match is_ancestor(target, user, wanted, &old_head)?
+                    && is_ancestor(target, user, &old_head, &fetched)?
+                {
+                    true => old_head.as_str(),
+                    false => wanted,
+                };
+                backup(target, user, reset_to)?;
+                process::run(
+                    git(&target.repo_path, user)?
+                        .args(["reset", "--hard"])
+                        .arg(reset_to),
+                    "git reset",
+                )?
+            }
+        }
+        let new_head = rev_parse(target, user, "HEAD")?;
+        check_head(
+            wanted,
+            &new_head,
+            is_ancestor(target, user, wanted, &new_head)?,
+        )?;
+        set_tracking(target, user, &fetched)?;
+
+        let changed_files = changed_files(target, user, &old_head, &new_head)?;
+
+        Ok(Update {
+            old_head,
+            new_head,
+            changed_files,
+        })
+    }
+
+    /// fetch the target's branch and return the commit to deploy out of it
+    pub fn fetch(target: &Target, user: &User, sha: Option<&str>) -> color_eyre::Result<String> {
+        let (_, fetched) = fetch_branch(target, user)?;
+        let wanted = sha.unwrap_or(&fetched);
+        check_on_branch(target, user, wanted, &fetched)?;
+        let wanted = rev_parse(target, user, wanted)?;
+        set_tracking(target, user, &fetched)?;
+        Ok(wanted)
+    }
+
+    /// fetch the target's branch into FETCH_HEAD and check if `sha` is on it
+    pub fn on_branch(target: &Target, user: &User, sha: &str) -> color_eyre::Result<bool> {
+        process::run(
+            git(&target.repo_path, user)?
+                .arg("fetch")
+                .arg(&target.remote)
+                .arg(format!("refs/heads/{}", target.branch)),
+            "git fetch",
+        )?;
+        // a commit that isn't on the branch may not have been fetched at all
+        let known = process::status(
+            git(&target.repo_path, user)?
+                .args(["cat-file", "-e"])
+                .arg(format!("{sha}^{{commit}}")),
+            "git cat-file",
+        )?;
+        Ok(known.success() && is_ancestor(target, user, sha, "FETCH_HEAD")?)
+    }
+
+    /// fetch the target's branch into FETCH_HEAD, returning the commit its
+    /// tracking ref points at (empty if it doesn't exist) and the fetched one
+    fn fetch_branch(target: &Target, user: &User) -> color_eyre::Result<(String, String)> {
+        // for-each-ref prints nothing instead of failing if the ref doesn't exist
+        let old_tracking = process::output(
+            git(&target.repo_path, user)?
+                .args(["for-each-ref", "--format=%(objectname)"])
+                .arg(tracking_ref(target)),
+            "git for-each-ref",
+        )?;
+        process::run(
+            git(&target.repo_path, user)?
+                .arg("fetch")
+                .arg(&target.remote)
+                .arg(format!("refs/heads/{}", target.branch)),
+            "git fetch",
+        )?;
+        let fetched = rev_parse(target, user, "FETCH_HEAD")?;
+        Ok((old_tracking, fetched))
+    }
+
+    /// move the tracking ref to a fetched commit once it's deployed, so a
+    /// rejected force push is still detected by the next deploy
+    fn set_tracking(target: &Target, user: &User, fetched: &str) -> color_eyre::Result<()> {
+        process::run(
+            git(&target.repo_path, user)?
+                .arg("update-ref")
+                .arg(tracking_ref(target))
+                .arg(fetched),
+            "git update-ref",
+        )
+    }
+
+    /// only deploy commits that were pushed to the branch
+    fn check_on_branch(
+        target: &Target,
+        user: &User,
+        wanted: &str,
+        fetched: &str,
+    ) -> color_eyre::Result<()> {
+        if !is_ancestor(target, user, wanted, fetched)? {
+            return Err(eyre!(
+                "{wanted} isn't on {} {}",
+                target.remote,
+                target.branch
+            ));
+        }
+        Ok(())
+    }
+
+    /// check out `sha`, keeping local changes that don't conflict with it
+    pub fn checkout(target: &Target, user: &User, sha: &str) -> color_eyre::Result<Update> {
+        let old_head = rev_parse(target, user, "HEAD")?;
+        tracing::info!("checking out {}", sha);
+        process::run(
+            git(&target.repo_path, user)?
+                .args(["reset", "--keep"])
+                .arg(sha),
+            "git reset",
+        )?;
+        let new_head = rev_parse(target, user, "HEAD")?;
+        let changed_files = changed_files(target, user, &old_head, &new_head)?;
+
+        Ok(Update {
+            old_head,
+            new_head,
+            changed_files,
+        })
+    }
+
+    /// find the commit a branch or tag on the target's remote points at
+    pub fn resolve_ref(target: &Target, user: &User, r#ref: &str) -> color_eyre::Result<String> {
+        let out = process::output(
+            git(&target.repo_path, user)?
+                .arg("ls-remote")
+                .arg(&target.remote)
+                .arg(r#ref),
+            "git ls-remote",
+        )?;
+        let refs = out
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(sha, name)| (sha.to_string(), name.to_string()))
+            .collect();
+
+        pick_ref(refs, r#ref)
+    }
+
+    /// the commit checked out in the target's repository
+    pub fn head(target: &Target, user: &User) -> color_eyre::Result<String> {
+        rev_parse(target, user, "HEAD")
+    }
+
+    /// paths changed between two commits
+    pub fn changed_files(
+        target: &Target,
+        user: &User,
+        old: &str,
+        new: &str,
+    ) -> color_eyre::Result<Vec<String>> {
+        if old == new {
+            return Ok(Vec::new());
+        }
+        let out = process::output(
+            git(&target.repo_path, user)?.args([
+                "diff",
+                "--name-only",
+                "--no-renames",
+                "-z",
+                old,
+                new,
+            ]),
+            "git diff",
+        )?;
+        Ok(out
+            .split('\0')
+            .filter(|path| !path.is_empty())
+            .map(ToString::to_string)
+            .collect())
+    }
+
+    /// resolve a revision to a commit
+    pub fn rev_parse(target: &Target, user: &User, rev: &str) -> color_eyre::Result<String> {
+        let out = process::output(
+            git(&target.repo_path, user)?
+                .args(["rev-parse", "--verify", "--end-of-options"])
+                .arg(format!("{rev}^{{commit}}")),
+            "git rev-parse",
+        )?;
+        Ok(out.trim().to_string())
+    }
+
+    /// check if `ancestor` is `commit` or one of its ancestors
+    fn is_ancestor(
+        target: &Target,
+        user: &User,
+        ancestor: &str,
+        commit: &str,
+    ) -> color_eyre::Result<bool> {
+        let status = process::status(
+            git(&target.repo_path, user)?.args(["merge-base", "--is-ancestor", ancestor, commit]),
+            "git merge-base",
+        )?;
+        match status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => Err(eyre!("{ancestor} or {commit} isn't a known commit")),
+        }
+    }
+
+    /// save uncommitted changes and commits that aren't in `wanted` to a backup
+    /// ref before they're reset away
+    fn backup(target: &Target, user: &User, wanted: &str) -> color_eyre::Result<()> {
+        // the stash commit is authored by shook so it works without a git identity
+        let stash = process::output(
+            git(&target.repo_path, user)?
+                .args(["stash", "create", "shook backup"])
+                .env("GIT_AUTHOR_NAME", "shook")
+                .env("GIT_AUTHOR_EMAIL", "shook@localhost")
+                .env("GIT_COMMITTER_NAME", "shook")
+                .env("GIT_COMMITTER_EMAIL", "shook@localhost"),
+            "git stash",
+        )?;
+        let stash = stash.trim();
+        let backup = match stash.is_empty() {
+            false => stash,
+            true if is_ancestor(target, user, "HEAD", wanted)? => return Ok(()),
+            true => "HEAD",
+        };
+
+        let name = backup_ref();
+        process::run(
+            git(&target.repo_path, user)?
+                .arg("update-ref")
+                .arg(&name)
+                .arg(backup),
+            "git update-ref",
+        )?;
+        tracing::warn!("saved local changes to {}", name);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "git2")]
+mod native {
+    use color_eyre::eyre::Context;
+    use git2::{
+        build::CheckoutBuilder, Commit, Config, Cred, CredentialType, Direction, ErrorCode,
+        FetchOptions, Oid, Remote, RemoteCallbacks, Repository, ResetType, Signature,
+    };
+
+    use super::*;
+
+    /// fetch the target's branch and apply `sha` (or the fetched branch) with
+    /// the target's strategy
+    pub fn update(target: &Target, sha: Option<&str>, forced: bool) -> color_eyre::Result<Update> {
+        let repo = Repository::open(&target.repo_path)
+            .with_context(|| format!("opening repository {:?}", target.repo_path))?;
+        let old = repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .context("reading HEAD")?;
+        let old_tracking = repo.refname_to_id(&tracking_ref(target)).ok();
+
+        let fetched = fetch(&repo, target)?;
+        let new = wanted(&repo, target, fetched, sha)?;
+        let forced = forced
+            || match old_tracking {
+                Some(old_tracking) => !is_ancestor(&repo, old_tracking, fetched)?,
+                None => false,
+            };
+        check_forced(target, forced)?;
+
+        tracing::info!("applying {} with {:?}", new.id(), target.update_strategy);
+        let up_to_date = is_ancestor(&repo, new.id(), old.id())?;
+        let fast_forward = is_ancestor(&repo, old.id(), new.id())?;
+        match target.update_strategy {
+            _ if up_to_date && target.update_strategy != UpdateStrategy::ResetHard => {
+                tracing::info!("{} is already merged", new.id());
+            }
+            UpdateStrategy::FfOnly | UpdateStrategy::Merge if fast_forward => {
+                tracing::info!("fast-forwarding {} to {}", old.id(), new.id());
+                repo.checkout_tree(new.as_object(), Some(CheckoutBuilder::new().safe()))
+                    .context("checking out new commit")?;
+                move_head(&repo, new.id(), "shook: fast-forward")?;
+            }
+            UpdateStrategy::FfOnly => {
+                return Err(eyre!(
+                    "can't fast-forward {} to {}, the checkout has local commits",
+                    old.id(),
+                    new.id()
+                ));
+            }
+            UpdateStrategy::Merge => {
+                tracing::info!("merging {} into {}", new.id(), old.id());
+                let mut index = repo
+                    .merge_commits(&old, &new, None)
+                    .context("merging commits")?;
+                if index.has_conflicts() {
+                    return Err(eyre!("merging {} has conflicts", new.id()));
+                }
+                let tree = repo.find_tree(index.write_tree_to(&repo)?)?;
+                repo.checkout_tree(tree.as_object(), Some(CheckoutBuilder::new().safe()))
+                    .context("checking out merged tree")?;
+                let signature = repo.signature().or_else(|_| shook_signature())?;
+                let message = format!("Merge {} of {}", target.branch, target.remote);
+                let merge =
+                    repo.commit(None, &signature, &signature, &message, &tree, &[&old, &new])?;
+                move_head(&repo, merge, "shook: merge")?;
+            }
+            UpdateStrategy::ResetHard => {
+                // a newer commit of the branch is already deployed, only discard
+                // local changes
+                let reset_to = match up_to_date && is_ancestor(&repo, old.id(), fetched)? {
+                    true => &old,
+                    false => &new,
+                };
+                let lost_head = !is_ancestor(&repo, old.id(), reset_to.id())?;
+                backup(&repo, lost_head.then(|| old.id()))?;
+                repo.reset(reset_to.as_object(), ResetType::Hard, None)
+                    .context("resetting to new commit")?;
+            }
+        }
+        let head = repo.head()?.peel_to_commit()?.id();
+        check_head(
+            &new.id().to_string(),
+            &head.to_string(),
+            is_ancestor(&repo, new.id(), head)?,
+        )?;
+        set_tracking(&repo, target, fetched)?;
+        changes(&repo, &old)
+    }
+
+    /// fetch the target's branch and return the commit to deploy out of it
+    pub fn fetch_commit(target: &Target, sha: Option<&str>) -> color_eyre::Result<String> {
+        let repo = Repository::open(&target.repo_path)
+            .with_context(|| format!("opening repository {:?}", target.repo_path))?;
+        let fetched = fetch(&repo, target)?;
+        let wanted = wanted(&repo, target, fetched, sha)?.id();
+        set_tracking(&repo, target, fetched)?;
+        Ok(wanted.to_string())
+    }
+
+    /// the commit to deploy: `sha`, or the tip of the fetched branch. only
+    /// commits that were pushed to the branch are deployed
+    fn wanted<'r>(
+        repo: &'r Repository,
+        target: &Target,
+        fetched: Oid,
+        sha: Option<&str>,
+    ) -> color_eyre::Result<Commit<'r>> {
+        let new = match sha {
+            Some(sha) => repo
+                .revparse_single(sha)
+                .and_then(|object| object.peel_to_commit())
+                .with_context(|| format!("commit {sha} wasn't fetched"))?,
+            None => repo.find_commit(fetched)?,
+        };
+        if !is_ancestor(repo, new.id(), fetched)? {
+            return Err(eyre!(
+                "{} isn't on {} {}",
+                new.id(),
+                target.remote,
+                target.branch
+            ));
+        }
+        Ok(new)
+    }
+
+    /// fetch the target's branch into FETCH_HEAD and check if `sha` is on it
+    pub fn on_branch(target: &Target, sha: &str) -> color_eyre::Result<bool> {
+        let repo = Repository::open(&target.repo_path)
+            .with_context(|| format!("opening repository {:?}", target.repo_path))?;
+        let fetched = fetch(&repo, target)?;
+        // a commit that isn't on the branch may not have been fetched at all
+        let Ok(commit) = repo
+            .revparse_single(sha)
+            .and_then(|object| object.peel_to_commit())
+        else {
+            return Ok(false);
+        };
+        is_ancestor(&repo, commit.id(), fetched)
+    }
+
+    /// the full id of a commit in the target's repository
+    pub fn resolve_commit(target: &Target, sha: &str) -> color_eyre::Result<String> {
+        let repo = Repository::open(&target.repo_path)
+            .with_context(|| format!("opening repository {:?}", target.repo_path))?;
+        let commit = repo
+            .revparse_single(sha)
+            .and_then(|object| object.peel_to_commit())
+            .with_context(|| format!("finding commit {sha}"))?;
+        Ok(commit.id().to_string())
+    }
+
+    /// check out `sha`, keeping local changes that don't conflict with it
+    pub fn checkout(target: &Target, sha: &str) -> color_eyre::Result<Update> {
+        let repo = Repository::open(&target.repo_path)
+            .with_context(|| format!("opening repository {:?}", target.repo_path))?;
+        let old = repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .context("reading HEAD")?;
+        let new = repo
+            .revparse_single(sha)
+            .and_then(|object| object.peel_to_commit())
+            .with_context(|| format!("finding commit {sha}"))?;
+
+        tracing::info!("checking out {}", new.id());
+        repo.checkout_tree(new.as_object(), Some(CheckoutBuilder::new().safe()))
+            .context("checking out commit")?;
+        move_head(&repo, new.id(), "shook: checkout")?;
+
+        changes(&repo, &old)
+    }
+
+    /// describe how HEAD changed from `old`
+    fn changes(repo: &Repository, old: &Commit) -> color_eyre::Result<Update> {
+        let new = repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .context("reading HEAD")?;
+
+        Ok(Update {
+            old_head: old.id().to_string(),
+            new_head: new.id().to_string(),
+            changed_files: diff(repo, old, &new)?,
+        })
+    }
+
+    /// paths changed between two commits
+    pub fn changed_files(target: &Target, old: &str, new: &str) -> color_eyre::Result<Vec<String>> {
+        let repo = Repository::open(&target.repo_path)
+            .with_context(|| format!("opening repository {:?}", target.repo_path))?;
+        let commit = |sha: &str| {
+            repo.revparse_single(sha)
+                .and_then(|object| object.peel_to_commit())
+                .with_context(|| format!("finding commit {sha}"))
+        };
+        let (old, new) = (commit(old)?, commit(new)?);
+        diff(&repo, &old, &new)
+    }
+
+    /// paths changed between two commits
+    fn diff(repo: &Repository, old: &Commit, new: &Commit) -> color_eyre::Result<Vec<String>> {
+        let diff = repo
+            .diff_tree_to_tree(Some(&old.tree()?), Some(&new.tree()?), None)
+            .context("comparing commits")?;
+        Ok(diff
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    /// check if `ancestor` is `commit` or one of its ancestors
+    fn is_ancestor(repo: &Repository, ancestor: Oid, commit: Oid) -> color_eyre::Result<bool> {
+        Ok(ancestor == commit || repo.graph_descendant_of(commit, ancestor)?)
+    }
+
+    /// point HEAD (or the branch it's on) at a commit whose tree is checked out
+    fn move_head(repo: &Repository, commit: Oid, message: &str) -> color_eyre::Result<()> {
+        let mut head = repo.head()?;
+        if head.is_branch() {
+            head.set_target(commit, message)?;
+        } else {
+            repo.set_head_detached(commit)?;
+        }
+        Ok(())
+    }
+
+    /// signature for commits made by shook when the user has no git identity
+    fn shook_signature() -> Result<Signature<'static>, git2::Error> {
+        Signature::now("shook", "shook@localhost")
+    }
+
+    /// save uncommitted changes, or `lost_head` if there are none, to a backup
+    /// ref before they're reset away
+    fn backup(repo: &Repository, lost_head: Option<Oid>) -> color_eyre::Result<()> {
+        let name = backup_ref();
+        let signature = shook_signature()?;
+        // stashing needs a mutable repository, and resets the working tree which is
+        // about to happen anyway
+        let mut stash_repo = Repository::open(repo.path())?;
+        let backup = match stash_repo.stash_save(&signature, "shook backup", None) {
+            Ok(stash) => {
+                stash_repo.stash_drop(0)?;
+                stash
+            }
+            Err(e) if e.code() == ErrorCode::NotFound => match lost_head {
+                Some(head) => head,
+                None => return Ok(()),
+            },
+            Err(e) => return Err(e).context("stashing local changes"),
+        };
+        repo.reference(&name, backup, false, "shook: backup")?;
+        tracing::warn!("saved local changes to {}", name);
+        Ok(())
+    }
+
+    /// fetch the target's branch into FETCH_HEAD, returning the fetched commit
+    fn fetch(repo: &Repository, target: &Target) -> color_eyre::Result<Oid> {
+        let mut remote = find_remote(repo, target)?;
+        let callbacks = callbacks(repo.config()?);
+
+        let refspec = format!("refs/heads/{}", target.branch);
+        tracing::info!("fetching {} from {}", target.branch, target.remote);
+        remote
+            .fetch(
+                &[&refspec],
+                Some(FetchOptions::new().remote_callbacks(callbacks)),
+                None,
+            )
+            .with_context(|| format!("fetching {} {}", target.remote, target.branch))?;
+
+        let mut fetched = None;
+        repo.fetchhead_foreach(|_, _, oid, _| {
+            fetched.get_or_insert(*oid);
+            true
+        })?;
+        fetched.ok_or_else(|| eyre!("{} wasn't fetched", target.branch))
+    }
+
+    /// move the tracking ref to a fetched commit once it's deployed, so a
+    /// rejected force push is still detected by the next deploy
+    fn set_tracking(repo: &Repository, target: &Target, fetched: Oid) -> color_eyre::Result<()> {
+        repo.reference(&tracking_ref(target), fetched, true, "shook: deployed")?;
+        Ok(())
+    }
+
+    /// the target's remote, either a configured remote or a url like `git
+    /// fetch` accepts
+    fn find_remote<'r>(repo: &'r Repository, target: &Target) -> color_eyre::Result<Remote<'r>> {
+        match repo.find_remote(&target.remote) {
+            Ok(remote) => Ok(remote),
+            // urls aren't valid remote names
+            Err(e) if matches!(e.code(), ErrorCode::NotFound | ErrorCode::InvalidSpec) => repo
+                .remote_anonymous(&target.remote)
+                .with_context(|| format!("finding remote {}", target.remote)),
+            Err(e) => Err(e).with_context(|| format!("finding remote {}", target.remote)),
+        }
+    }
+
+    /// find the commit a branch or tag on the target's remote points at
+    pub fn resolve_ref(target: &Target, r#ref: &str) -> color_eyre::Result<String> {
+        let repo = Repository::open(&target.repo_path)
+            .with_context(|| format!("opening repository {:?}", target.repo_path))?;
+        let mut remote = find_remote(&repo, target)?;
+        let connection = remote
+            .connect_auth(Direction::Fetch, Some(callbacks(repo.config()?)), None)
+            .with_context(|| format!("connecting to {}", target.remote))?;
+        let refs = connection
+            .list()?
+            .iter()
+            .map(|head| (head.oid().to_string(), head.name().to_string()))
+            .collect::<Vec<_>>();
+
+        pick_ref(refs, r#ref)
+    }
+
+    /// the commit checked out in the target's repository
+    pub fn head(target: &Target) -> color_eyre::Result<String> {
+        let repo = Repository::open(&target.repo_path)
+            .with_context(|| format!("opening repository {:?}", target.repo_path))?;
+        let head = repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .context("reading HEAD")?;
+        Ok(head.id().to_string())
+    }
+
+    /// callbacks answering credential requests with the user's ssh keys, ssh
+    /// agent or git credential helper
+    fn callbacks(config: Config) -> RemoteCallbacks<'static> {
+        // libgit2 keeps asking for credentials until the callback gives up, so
+        // each kind is only tried once
+        let mut tried = CredentialType::empty();
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(move |url, username, allowed| {
+            let username = username.unwrap_or("git");
+            if allowed.contains(CredentialType::SSH_KEY) && !tried.contains(CredentialType::SSH_KEY)
+            {
+                tried |= CredentialType::SSH_KEY;
+                if let Some(key) = ssh_key() {
+                    return Cred::ssh_key(username, None, &key, None);
+                }
+                return Cred::ssh_key_from_agent(username);
+            }
+            if allowed.contains(CredentialType::USER_PASS_PLAINTEXT)
+                && !tried.contains(CredentialType::USER_PASS_PLAINTEXT)
+            {
+                tried |= CredentialType::USER_PASS_PLAINTEXT;
+                return Cred::credential_helper(&config, url, Some(username));
+            }
+            if allowed.contains(CredentialType::DEFAULT) && !tried.contains(CredentialType::DEFAULT)
+            {
+                tried |= CredentialType::DEFAULT;
+                return Cred::default();
+            }
+            Err(git2::Error::from_str("no credentials accepted"))
+        });
+        // libgit2 can't be killed like git, so stop the transfer once the pull
+        // times out or the deploy is cancelled
+        callbacks.transfer_progress(|_| process::stopped().is_none());
+        callbacks
+    }
+
+    /// the first of the user's default ssh keys that exists
+    fn ssh_key() -> Option<std::path::PathBuf> {
+        let home = std::env::var_os("HOME")?;
+        ["id_ed25519", "id_ecdsa", "id_rsa"]
+            .iter()
+            .map(|name| Path::new(&home).join(".ssh").join(name))
+            .find(|path| path.exists())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    /// an `origin` repository, a `work` clone pushing to it and an `app` clone
+    /// that's deployed
+    struct Scratch {
+        dir: PathBuf,
+        backend: GitBackend,
+    }
+
+    impl Scratch {
+        fn new(name: &str, backend: GitBackend) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "shook-git-{}-{name}-{backend:?}",
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let scratch = Self { dir, backend };
+            scratch.git(".", &["init", "-q", "--bare", "-b", "main", "origin.git"]);
+            scratch.git(".", &["clone", "-q", "origin.git", "work"]);
+            scratch.commit("one");
+            scratch.git(".", &["clone", "-q", "origin.git", "app"]);
+            for repo in ["work", "app"] {
+                scratch.git(repo, &["config", "user.name", "test"]);
+                scratch.git(repo, &["config", "user.email", "test@localhost"]);
+            }
+            scratch
+        }
+
+        fn git(&self, repo: &str, args: &[&str]) -> String {
+            let out = Command::new("git")
+                .current_dir(self.dir.join(repo))
+                .args(args)
+                .env("GIT_AUTHOR_NAME", "test")
+                .env("GIT_AUTHOR_EMAIL", "test@localhost")
+                .env("GIT_COMMITTER_NAME", "test")
+                .env("GIT_COMMITTER_EMAIL", "test@localhost")
+                .output()
+                .unwrap();
+            assert!(out.status.success(), "git {args:?} failed: {out:?}");
+            String::from_utf8(out.stdout).unwrap().trim().to_string()
+        }
+
+        /// commit a new file called `name` in `work` and push it
+        fn commit(&self, name: &str) -> String {
+            fs::write(self.dir.join("work").join(name), name).unwrap();
+            self.git("work", &["add", name]);
+            self.git("work", &["commit", "-q", "-m", name]);
+            self.git("work", &["push", "-q", "-f", "origin", "main"]);
+            self.git("work", &["rev-parse", "HEAD"])
+        }
+
+        fn target(&self, strategy: UpdateStrategy) -> Target {
+            let user = User::from_uid(Uid::current()).unwrap().unwrap();
+            let strategy = match strategy {
+                UpdateStrategy::FfOnly => "ff-only",
+                UpdateStrategy::Merge => "merge",
+                UpdateStrategy::ResetHard => "reset-hard",
+            };
+            let backend = match self.backend {
+                GitBackend::Cli => "cli",
+                GitBackend::Git2 => "git2",
+            };
+            toml::from_str(&format!(
+                r#"
+                username = "{}"
+                repo_path = "{}"
+                remote = "origin"
+                branch = "main"
+                system_name = "app.service"
+                update_strategy = "{strategy}"
+                git_backend = "{backend}"
+                "#,
+                user.name,
+                self.dir.join("app").display(),
+            ))
+            .unwrap()
+        }
+
+        fn update(&self, strategy: UpdateStrategy, sha: &str) -> color_eyre::Result<Update> {
+            let user = User::from_uid(Uid::current()).unwrap().unwrap();
+            update(&self.target(strategy), &user, Some(sha), false)
+        }
+
+        fn tracking(&self) -> String {
+            self.git("app", &["rev-parse", "refs/shook/tracking/main"])
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn backends() -> Vec<GitBackend> {
+        match cfg!(feature = "git2") {
+            true => vec![GitBackend::Cli, GitBackend::Git2],
+            false => vec![GitBackend::Cli],
+        }
+    }
+
+    #[test]
+    fn ff_only() {
+        for backend in backends() {
+            let scratch = Scratch::new("ff-only", backend);
+            let two = scratch.commit("two");
+
+            let update = scratch.update(UpdateStrategy::FfOnly, &two).unwrap();
+            assert_eq!(update.new_head, two);
+            assert_eq!(update.changed_files, ["two"]);
+            assert_eq!(scratch.tracking(), two);
+        }
+    }
+
+    #[test]
+    fn merge_local_commit() {
+        for backend in backends() {
+            let scratch = Scratch::new("merge", backend);
+            fs::write(scratch.dir.join("app/local"), "local").unwrap();
+            scratch.git("app", &["add", "local"]);
+            scratch.git("app", &["commit", "-q", "-m", "local"]);
+            let two = scratch.commit("two");
+
+            let update = scratch.update(UpdateStrategy::Merge, &two).unwrap();
+            assert_ne!(update.new_head, two);
+            assert_eq!(scratch.git("app", &["rev-parse", "HEAD^2"]), two);
+            assert_eq!(update.changed_files, ["two"]);
+            assert_eq!(scratch.tracking(), two);
+        }
+    }
+
+    #[test]
+    fn reset_hard_saves_local_changes() {
+        for backend in backends() {
+            let scratch = Scratch::new("reset-hard", backend);
+            fs::write(scratch.dir.join("app/one"), "changed").unwrap();
+            let two = scratch.commit("two");
+
+            let update = scratch.update(UpdateStrategy::ResetHard, &two).unwrap();
+            assert_eq!(update.new_head, two);
+            assert_eq!(
+                fs::read_to_string(scratch.dir.join("app/one")).unwrap(),
+                "one"
+            );
+            let backups = scratch.git("app", &["for-each-ref", "refs/shook/backup"]);
+            assert!(!backups.is_empty());
+        }
+    }
+
+    #[test]
+    fn older_commit_keeps_newer_head() {
+        for backend in backends() {
+            let scratch = Scratch::new("older", backend);
+            let two = scratch.commit("two");
+            let three = scratch.commit("three");
+            scratch.update(UpdateStrategy::FfOnly, &three).unwrap();
+
+            for strategy in [UpdateStrategy::Merge, UpdateStrategy::ResetHard] {
+                let update = scratch.update(strategy, &two).unwrap();
+                assert_eq!(update.new_head, three);
+            }
+        }
+    }
+
+    #[test]
+    fn head_must_contain_wanted() {
+        let wanted = "a3f1c07d8e1b8e4c1c7a7b5f2f1a6b3d9c0e4f21";
+        check_head(wanted, wanted, true).unwrap();
+        check_head("A3F1C07", wanted, false).unwrap();
+        check_head(wanted, "056f7d95bf8857db90986202dc9ed3e6d94f55a8", true).unwrap();
+        assert!(check_head(wanted, "056f7d95bf8857db90986202dc9ed3e6d94f55a8", false).is_err());
+    }
+
+    #[test]
+    fn refused_force_push_stays_refused() {
+        for backend in backends() {
+            let scratch = Scratch::new("force-push", backend);
+            let two = scratch.commit("two");
+            scratch.update(UpdateStrategy::Merge, &two).unwrap();
+
+            scratch.git("work", &["reset", "-q", "--hard", "HEAD^"]);
+            let rewritten = scratch.commit("rewritten");
+            let e = scratch
+                .update(UpdateStrategy::Merge, &rewritten)
+                .unwrap_err();
+            assert!(e.to_string().contains("force pushed"), "{e}");
+            assert_eq!(scratch.tracking(), two);
+
+            // a normal push on top of the rewritten history is still a force push
+            let three = scratch.commit("three");
+            let e = scratch.update(UpdateStrategy::Merge, &three).unwrap_err();
+            assert!(e.to_string().contains("force pushed"), "{e}");
+            assert_eq!(scratch.git("app", &["rev-parse", "HEAD"]), two);
+
+            let update = scratch.update(UpdateStrategy::ResetHard, &three).unwrap();
+            assert_eq!(update.new_head, three);
+            assert_eq!(scratch.tracking(), three);
+        }
+    }
+}
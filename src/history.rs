@@ -0,0 +1,257 @@
+//! a record of the latest deploys, kept as json lines in the repository's
+//! `.git` directory so `shook rollback` can find earlier commits. the output
+//! of each deploy is kept in its own file next to it
+use std::{
+    collections::VecDeque,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::Context;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{config::Target, process::OutputLine, server::DeployKind};
+
+/// how many deploys the history keeps, older ones are dropped along with their
+/// output
+const MAX_DEPLOYMENTS: usize = 200;
+
+/// a finished deploy
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Deployment {
+    /// id of the delivery or command that started the deploy
+    pub guid: Uuid,
+    /// name of the target, `None` is the top level target
+    pub target: Option<String>,
+    pub kind: DeployKind,
+    /// unix time the deploy finished at
+    pub time: u64,
+    /// commit checked out before the deploy
+    pub previous: Option<String>,
+    /// commit that was deployed, or was being deployed when it failed
+    pub sha: Option<String>,
+    pub success: bool,
+    /// superseded by a newer deploy before it finished. it may have moved the
+    /// checkout, but didn't fail
+    #[serde(default)]
+    pub cancelled: bool,
+    /// the newest lines written by the deploy's commands, recorded to
+    /// `.git/shook-output/<guid>.json` instead of the history
+    #[serde(default, skip_serializing)]
+    pub output: VecDeque<OutputLine>,
+    /// number of older lines left out of `output`
+    #[serde(default, skip_serializing)]
+    pub output_dropped: usize,
+}
+
+/// what's written to a deploy's output file
+#[derive(Serialize)]
+struct Output<'a> {
+    output: &'a VecDeque<OutputLine>,
+    output_dropped: usize,
+}
+
+impl Deployment {
+    /// unix time right now, for `time`
+    pub fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+/// file the deploys of a target's repository are recorded in
+fn history_path(target: &Target) -> PathBuf {
+    target.repo_path.join(".git").join("shook-history.jsonl")
+}
+
+/// file the output of a deploy is recorded in
+fn output_path(target: &Target, guid: Uuid) -> PathBuf {
+    target
+        .repo_path
+        .join(".git")
+        .join("shook-output")
+        .join(format!("{guid}.json"))
+}
+
+/// the lines of the history file, empty if nothing was recorded yet
+fn lines(target: &Target) -> color_eyre::Result<Vec<String>> {
+    let path = history_path(target);
+    match fs::read_to_string(&path) {
+        Ok(history) => Ok(history
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(ToString::to_string)
+            .collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("reading deploy history {path:?}")),
+    }
+}
+
+/// parse the history entries of the target called `name`
+fn parse<'a>(
+    lines: impl Iterator<Item = &'a String> + 'a,
+    name: Option<&'a str>,
+) -> impl Iterator<Item = Deployment> + 'a {
+    lines.filter_map(move |line| {
+        // targets can share a repository
+        match serde_json::from_str::<Deployment>(line) {
+            Ok(deployment) if deployment.target.as_deref() == name => Some(deployment),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::warn!("skipping invalid deploy history entry: {e}");
+                None
+            }
+        }
+    })
+}
+
+/// record a deploy and its output, dropping the oldest deploys once there are
+/// more than `MAX_DEPLOYMENTS`
+pub fn record(target: &Target, deployment: &Deployment) -> color_eyre::Result<()> {
+    let output = output_path(target, deployment.guid);
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("creating {dir:?}"))?;
+    }
+    let json = serde_json::to_vec(&Output {
+        output: &deployment.output,
+        output_dropped: deployment.output_dropped,
+    })
+    .context("serializing deploy output")?;
+    fs::write(&output, json).with_context(|| format!("writing deploy output {output:?}"))?;
+
+    let path = history_path(target);
+    let mut lines = lines(target)?;
+    let mut line = serde_json::to_string(deployment).context("serializing deployment")?;
+    if lines.len() < MAX_DEPLOYMENTS {
+        line.push('\n');
+        return OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("writing deploy history {path:?}"));
+    }
+
+    lines.push(line);
+    let dropped: Vec<String> = lines.drain(..lines.len() - MAX_DEPLOYMENTS).collect();
+    // rewritten next to the history and renamed over it, so it's never half written
+    let temp = path.with_extension("jsonl.tmp");
+    fs::write(&temp, lines.join("\n") + "\n")
+        .and_then(|()| fs::rename(&temp, &path))
+        .with_context(|| format!("writing deploy history {path:?}"))?;
+    for line in dropped {
+        if let Ok(old) = serde_json::from_str::<Deployment>(&line) {
+            match fs::remove_file(output_path(target, old.guid)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    tracing::warn!("failed to remove the output of deploy {}: {e}", old.guid);
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// every recorded deploy of a target, oldest first
+pub fn read(target: &Target, name: Option<&str>) -> color_eyre::Result<Vec<Deployment>> {
+    let lines = lines(target)?;
+    Ok(parse(lines.iter(), name).collect())
+}
+
+/// the newest deploy of a target that wasn't cancelled, and whether it's the
+/// newest deploy of the target at all
+pub fn last_finished(
+    target: &Target,
+    name: Option<&str>,
+) -> color_eyre::Result<Option<(Deployment, bool)>> {
+    let lines = lines(target)?;
+    let last = parse(lines.iter().rev(), name)
+        .enumerate()
+        .find(|(_, deployment)| !deployment.cancelled)
+        .map(|(newer, deployment)| (deployment, newer == 0));
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment(sha: &str, success: bool, cancelled: bool) -> Deployment {
+        Deployment {
+            guid: Uuid::new_v4(),
+            target: None,
+            kind: DeployKind::Update,
+            time: Deployment::now(),
+            previous: None,
+            sha: Some(sha.to_string()),
+            success,
+            cancelled,
+            output: VecDeque::from([OutputLine {
+                command: "build".to_string(),
+                stream: "stdout".to_string(),
+                line: format!("built {sha}"),
+            }]),
+            output_dropped: 0,
+        }
+    }
+
+    fn target(name: &str) -> Target {
+        let dir = std::env::temp_dir().join(format!("shook-history-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        toml::from_str(&format!(
+            r#"
+            username = "root"
+            repo_path = "{}"
+            remote = "origin"
+            branch = "main"
+            system_name = "app.service"
+            "#,
+            dir.display()
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn drops_oldest_deploys() {
+        let target = target("rotate");
+        let deployments: Vec<_> = (0..MAX_DEPLOYMENTS + 5)
+            .map(|i| deployment(&i.to_string(), true, false))
+            .collect();
+        for deployment in &deployments {
+            record(&target, deployment).unwrap();
+        }
+
+        let history = read(&target, None).unwrap();
+        assert_eq!(history.len(), MAX_DEPLOYMENTS);
+        assert_eq!(history[0].sha.as_deref(), Some("5"));
+        assert!(history.iter().all(|d| d.output.is_empty()));
+        assert!(!output_path(&target, deployments[4].guid).exists());
+        let output = fs::read_to_string(output_path(&target, deployments[5].guid)).unwrap();
+        assert!(output.contains("built 5"));
+
+        fs::remove_dir_all(&target.repo_path).unwrap();
+    }
+
+    #[test]
+    fn last_finished_skips_cancelled() {
+        let target = target("last");
+        assert!(last_finished(&target, None).unwrap().is_none());
+
+        record(&target, &deployment("a", true, false)).unwrap();
+        let (last, newest) = last_finished(&target, None).unwrap().unwrap();
+        assert_eq!((last.sha.as_deref(), newest), (Some("a"), true));
+
+        record(&target, &deployment("b", false, true)).unwrap();
+        let (last, newest) = last_finished(&target, None).unwrap().unwrap();
+        assert_eq!((last.sha.as_deref(), newest), (Some("a"), false));
+        assert!(last_finished(&target, Some("other")).unwrap().is_none());
+
+        fs::remove_dir_all(&target.repo_path).unwrap();
+    }
+}
@@ -1,20 +1,27 @@
 use std::{
     fs::{self, File},
     io::{Read, Write},
+    os::fd::AsRawFd,
     path::{Path, PathBuf},
-    process::Command,
     str::FromStr,
 };
 
 use color_eyre::eyre::{eyre, Context};
 use dialoguer::{theme::ColorfulTheme, Completion, Confirm, Input};
 use github_webhook_extract::EventDiscriminants;
+use nix::unistd::{chown, fchown};
 use text_completions::{EnvCompletion, MultiCompletion, PathCompletion};
 
-use crate::config::{parse_multiple_events, Init, InitConfig, TcpOrUnix};
+use crate::{
+    config::{default_providers, parse_multiple_events, Init, InitConfig, TcpOrUnix},
+    process, user,
+};
 
 const SERVICE_TEMPLATE: &str = include_str!("shook.service");
+const SOCKET_TEMPLATE: &str = include_str!("shook.socket");
 const SERVICE_DIR: &str = "/etc/systemd/system/";
+/// where user units go, relative to the user's home
+const USER_SERVICE_DIR: &str = ".config/systemd/user";
 
 pub fn init_project(args: Init) -> color_eyre::Result<()> {
     tracing::info!("creating project");
@@ -92,10 +99,15 @@ pub fn init_project(args: Init) -> color_eyre::Result<()> {
         addr,
         socket_group,
         socket_user,
+        socket_mode: args.socket_mode.unwrap_or(0o666),
         pre_restart_command,
         shook_service_name: args
             .shook_service_name
             .unwrap_or_else(|| "shook.service".to_string()),
+        providers: args.providers.unwrap_or_else(default_providers),
+        socket_activation: args.socket_activation,
+        systemd_backend: Default::default(),
+        user_mode: args.user_mode,
     };
 
     tracing::debug!(?config);
@@ -122,14 +134,11 @@ pub fn init_project(args: Init) -> color_eyre::Result<()> {
                 fs::create_dir_all(parent)?;
             }
 
-            let mut handle = Command::new("su")
-                .arg(&config.username)
-                .arg("-c")
-                .arg(format!(
-                    "git clone '{}' '{}'",
-                    url,
-                    config.repo_path.to_string_lossy()
-                ))
+            let user = user::user(&config.username)?;
+            let mut handle = process::command_as("git", &user)?
+                .arg("clone")
+                .arg(&url)
+                .arg(&config.repo_path)
                 .current_dir(parent)
                 .spawn()?;
             let exit_code = handle.wait()?;
@@ -165,17 +174,31 @@ pub fn init_project(args: Init) -> color_eyre::Result<()> {
 }
 
 fn install(config: &mut InitConfig) -> color_eyre::Result<()> {
-    let systemd = SERVICE_TEMPLATE.replace(
-        "{REPO_PATH}",
-        config
-            .repo_path
-            .to_str()
-            .ok_or_else(|| eyre!("repo path is not vaid utf8"))?,
-    );
+    // user services can't write to /var/log, so they log to ~/.local/state/shook
+    let (service_options, log_file, wanted_by) = if config.user_mode {
+        (
+            "StateDirectory=shook\n",
+            "%S/shook/shook.log",
+            "default.target",
+        )
+    } else {
+        ("", "/var/log/shook.log", "multi-user.target")
+    };
+    let systemd = SERVICE_TEMPLATE
+        .replace(
+            "{REPO_PATH}",
+            config
+                .repo_path
+                .to_str()
+                .ok_or_else(|| eyre!("repo path is not vaid utf8"))?,
+        )
+        .replace("{SERVICE_OPTIONS}", service_options)
+        .replace("{LOG_FILE}", log_file)
+        .replace("{WANTED_BY}", wanted_by);
 
     tracing::info!("installing systemd config");
     tracing::debug!("systemd file:\n{}", systemd);
-    let mut service_path = PathBuf::from(SERVICE_DIR);
+    let mut service_path = unit_dir(config)?;
     service_path.push(&config.shook_service_name);
     if Path::exists(&service_path) {
         tracing::warn!("shook.service already exists");
@@ -210,15 +233,96 @@ fn install(config: &mut InitConfig) -> color_eyre::Result<()> {
         }
     }
 
-    let mut file = File::create(&service_path).context("creating service file")?;
+    let mut file = create_unit(config, &service_path).context("creating service file")?;
     file.write_all(systemd.as_bytes())
         .context("writing service file")?;
 
+    if config.socket_activation {
+        install_socket(config)?;
+    }
+    if config.user_mode {
+        tracing::info!(
+            "run `loginctl enable-linger {}` to start its services at boot instead of at login",
+            config.username
+        );
+    }
+
     tracing::info!("finished creating project");
 
     Ok(())
 }
 
+/// install a .socket unit listening on the configured address
+fn install_socket(config: &InitConfig) -> color_eyre::Result<()> {
+    let socket_options = match &config.addr {
+        // the user manager can't change the owner of its sockets
+        TcpOrUnix::Unix(_) if config.user_mode => {
+            format!("SocketMode={:04o}\nRemoveOnStop=true\n", config.socket_mode)
+        }
+        TcpOrUnix::Unix(_) => format!(
+            "SocketUser={}\nSocketGroup={}\nSocketMode={:04o}\nRemoveOnStop=true\n",
+            config.socket_user, config.socket_group, config.socket_mode
+        ),
+        TcpOrUnix::Tcp(_) => String::new(),
+    };
+    let socket = SOCKET_TEMPLATE
+        .replace("{LISTEN_STREAM}", &config.addr.to_string())
+        .replace("{SOCKET_OPTIONS}", &socket_options);
+
+    tracing::info!("installing systemd socket");
+    tracing::debug!("systemd file:\n{}", socket);
+    let socket_path = unit_dir(config)?.join(config.shook_socket_name());
+    if Path::exists(&socket_path) {
+        tracing::warn!("{:?} already exists", socket_path);
+
+        let should_replace = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("replace existing socket file?")
+            .interact()?;
+        if !should_replace {
+            tracing::info!("not replacing socket file");
+            return Ok(());
+        }
+    }
+
+    let mut file = create_unit(config, &socket_path).context("creating socket file")?;
+    file.write_all(socket.as_bytes())
+        .context("writing socket file")?;
+
+    Ok(())
+}
+
+/// the directory shook's units are installed into: the system manager's, or
+/// `~/.config/systemd/user` of `username` in user mode, created if it's missing
+fn unit_dir(config: &InitConfig) -> color_eyre::Result<PathBuf> {
+    if !config.user_mode {
+        return Ok(PathBuf::from(SERVICE_DIR));
+    }
+
+    let user = user::user(&config.username)?;
+    let mut dir = user.dir.clone();
+    for part in Path::new(USER_SERVICE_DIR) {
+        dir.push(part);
+        if !Path::try_exists(&dir)? {
+            tracing::info!("creating {:?}", dir);
+            fs::create_dir(&dir).with_context(|| format!("creating {dir:?}"))?;
+            // init may run as root
+            chown(&dir, Some(user.uid), Some(user.gid))
+                .with_context(|| format!("changing owner of {dir:?}"))?;
+        }
+    }
+    Ok(dir)
+}
+
+/// create a unit file, owned by `username` in user mode
+fn create_unit(config: &InitConfig, path: &Path) -> color_eyre::Result<File> {
+    let file = File::create(path)?;
+    if config.user_mode {
+        let user = user::user(&config.username)?;
+        fchown(file.as_raw_fd(), Some(user.uid), Some(user.gid))?;
+    }
+    Ok(file)
+}
+
 fn get_input<T>(prompt: &str, initial: Option<T>) -> color_eyre::Result<T>
 where
     T: Clone + ToString + FromStr,
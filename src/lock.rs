@@ -0,0 +1,54 @@
+//! a lock on a target's repository, held while deploying so `shook serve`,
+//! `shook deploy` and `shook rollback` don't change the same checkout, release
+//! symlink and history at once
+use std::{
+    fs::{File, OpenOptions},
+    os::fd::AsRawFd,
+    thread,
+    time::Duration,
+};
+
+use color_eyre::eyre::Context;
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+};
+
+use crate::{config::Target, process};
+
+/// how often to try taking a lock held by another deploy
+const LOCK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// a held lock, released when it's dropped
+#[derive(Debug)]
+pub struct RepoLock {
+    _file: File,
+}
+
+/// lock the target's repository, waiting for another deploy holding it to
+/// finish. gives up if this thread's deploy is cancelled while waiting
+pub fn lock(target: &Target) -> color_eyre::Result<RepoLock> {
+    let path = target.repo_path.join(".git").join("shook.lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("opening lock file {path:?}"))?;
+
+    let mut waiting = false;
+    loop {
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => return Ok(RepoLock { _file: file }),
+            Err(Errno::EWOULDBLOCK) => {
+                if !waiting {
+                    tracing::info!("waiting for another deploy of {:?}", target.repo_path);
+                    waiting = true;
+                }
+                process::check_stopped()?;
+                thread::sleep(LOCK_INTERVAL);
+            }
+            Err(e) => return Err(e).with_context(|| format!("locking {path:?}")),
+        }
+    }
+}
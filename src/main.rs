@@ -1,8 +1,23 @@
+mod blue_green;
 mod config;
 mod daemon;
+mod deploy;
+mod deploy_hook;
 mod error;
+mod git;
+mod history;
 mod init;
+mod lock;
+mod pipeline;
+mod process;
+mod release;
+mod rollback;
 mod server;
+mod systemd;
+mod tls;
+mod units;
+mod user;
+mod webhook;
 
 use std::fs::OpenOptions;
 
@@ -36,7 +51,9 @@ async fn main() -> color_eyre::Result<()> {
     match args.action {
         Action::Init(init) => init::init_project(init),
         Action::Serve(serve) => server::serve(serve).await,
-        Action::Daemon(daemon) => daemon::daemon_message(daemon),
+        Action::Daemon(daemon) => blocking(|| daemon::daemon_message(daemon)).await,
+        Action::Deploy(deploy) => blocking(|| deploy::deploy(deploy)).await,
+        Action::Rollback(rollback) => blocking(|| rollback::rollback(rollback)).await,
         Action::Version(_) => {
             println!("shook version: {}", env!("CARGO_PKG_VERSION"));
             Ok(())
@@ -45,3 +62,10 @@ async fn main() -> color_eyre::Result<()> {
 
     Ok(())
 }
+
+/// run a command that blocks on git, builds or systemd off the async runtime
+async fn blocking(
+    f: impl FnOnce() -> color_eyre::Result<()> + Send + 'static,
+) -> color_eyre::Result<()> {
+    tokio::task::spawn_blocking(f).await?
+}
@@ -0,0 +1,138 @@
+//! the commands run around restarting a target's service: the
+//! `pre_restart_command` followed by the target's `[[steps]]`
+use std::{
+    collections::HashSet,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{eyre, Context};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use nix::unistd::User;
+use tracing::instrument;
+
+use crate::{
+    config::{Stage, Step, Target},
+    process, user,
+};
+
+/// make sure the steps are usable when shook starts
+pub fn check(target: &Target) -> color_eyre::Result<()> {
+    let mut names = HashSet::new();
+    for step in &target.steps {
+        if step.name.is_empty() || !names.insert(&step.name) {
+            return Err(eyre!("step name is empty or used twice: {:?}", step.name));
+        }
+        if let Some(name) = &step.user {
+            user::user(name).with_context(|| format!("finding user for step {}", step.name))?;
+        }
+        globs(&step.paths).with_context(|| format!("parsing paths of step {}", step.name))?;
+    }
+    globs(&target.restart_paths).context("parsing restart_paths")?;
+    for service in &target.services {
+        globs(&service.paths).with_context(|| format!("parsing paths of {}", service.name))?;
+    }
+    Ok(())
+}
+
+/// compile path globs. `*` doesn't match `/`, `**` matches any number of
+/// directories
+fn globs(paths: &[String]) -> color_eyre::Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for path in paths {
+        set.add(GlobBuilder::new(path).literal_separator(true).build()?);
+    }
+    Ok(set.build()?)
+}
+
+/// check if a deploy touched any of `paths`. no paths, or not knowing what
+/// changed, counts as touched
+pub fn touches(paths: &[String], changed: Option<&[String]>) -> color_eyre::Result<bool> {
+    let Some(changed) = changed else {
+        return Ok(true);
+    };
+    if paths.is_empty() {
+        return Ok(true);
+    }
+    let globs = globs(paths)?;
+    Ok(changed.iter().any(|file| globs.is_match(file)))
+}
+
+/// the steps of a stage in the order they run
+pub fn steps(target: &Target, stage: Stage) -> Vec<Step> {
+    let pre_restart =
+        (stage == Stage::PreRestart && !target.pre_restart_command.is_empty()).then(|| Step {
+            name: "pre-restart".to_string(),
+            command: target.pre_restart_command.clone(),
+            stage,
+            dir: None,
+            env: Default::default(),
+            timeout: None,
+            continue_on_error: false,
+            user: None,
+            paths: Vec::new(),
+        });
+
+    pre_restart
+        .into_iter()
+        .chain(target.steps.iter().filter(|s| s.stage == stage).cloned())
+        .collect()
+}
+
+/// run the steps of a stage in `dir`, the checkout being deployed. steps whose
+/// paths aren't in `changed` are skipped, and the first failing step stops
+/// the deploy unless it's allowed to fail
+#[instrument(skip_all, fields(?stage))]
+pub fn run(
+    target: &Target,
+    user: &User,
+    stage: Stage,
+    dir: &Path,
+    changed: Option<&[String]>,
+) -> color_eyre::Result<()> {
+    for step in steps(target, stage) {
+        if !touches(&step.paths, changed)? {
+            tracing::info!("skipping step {}, none of its paths changed", step.name);
+            continue;
+        }
+        match run_step(user, &step, dir) {
+            Ok(()) => {}
+            // a timed out or cancelled deploy stops even if the step may fail
+            Err(e) if step.continue_on_error && process::stopped().is_none() => {
+                tracing::warn!("step {} failed, continuing: {e}", step.name);
+            }
+            Err(e) => return Err(e).with_context(|| format!("step {} failed", step.name)),
+        }
+    }
+
+    Ok(())
+}
+
+/// run one step as its user, or the target's user
+#[instrument(skip_all, fields(step = %step.name))]
+fn run_step(user: &User, step: &Step, dir: &Path) -> color_eyre::Result<()> {
+    let step_user = match &step.user {
+        Some(name) => user::user(name)?,
+        None => user.clone(),
+    };
+    let dir = match &step.dir {
+        Some(sub) => dir.join(sub),
+        None => dir.to_path_buf(),
+    };
+    tracing::info!("running `{}` in {:?}", step.command, dir);
+
+    let start = Instant::now();
+    process::with_timeout(step.timeout.map(Duration::from_secs), || {
+        process::run(
+            process::command_as("sh", &step_user)?
+                .arg("-c")
+                .arg(&step.command)
+                .current_dir(&dir)
+                .envs(&step.env),
+            &step.name,
+        )
+    })?;
+    tracing::info!("step {} succeeded in {:?}", step.name, start.elapsed());
+
+    Ok(())
+}
@@ -0,0 +1,432 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    ffi::{CString, OsStr},
+    io::{BufRead, BufReader, Read},
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{eyre, Context};
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::{getgrouplist, setgid, setgroups, setuid, Pid, Uid, User},
+};
+use serde::{Deserialize, Serialize};
+use tracing::Span;
+
+/// environment variables holding shook's secrets or sockets, which shouldn't
+/// leak into git or hooks
+const PRIVATE_ENV: &[&str] = &[
+    "GITHUB_TOKEN",
+    "GITEA_TOKEN",
+    "BITBUCKET_TOKEN",
+    "LISTEN_PID",
+    "LISTEN_FDS",
+    "LISTEN_FDNAMES",
+];
+
+/// how long to keep reading a command's output after it exits
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// how long a stopped command has to exit after SIGTERM before it's killed
+const STOP_GRACE: Duration = Duration::from_secs(10);
+
+/// longest line of output logged, longer lines are split
+const MAX_LINE: u64 = 4096;
+
+/// bytes of output kept for a deploy's history entry, older lines are dropped
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// build a command that runs as `user` without going through `su`. HOME, USER,
+/// LOGNAME and SHELL are set to the user's, like `su` does
+pub fn command_as(program: impl AsRef<OsStr>, user: &User) -> color_eyre::Result<Command> {
+    let mut command = Command::new(program);
+    command
+        .env("HOME", &user.dir)
+        .env("USER", &user.name)
+        .env("LOGNAME", &user.name)
+        .env("SHELL", &user.shell);
+    for var in PRIVATE_ENV {
+        command.env_remove(var);
+    }
+
+    // nothing to switch to when running as the user already (e.g. running
+    // without root)
+    if user.uid == Uid::current() {
+        return Ok(command);
+    }
+
+    // the group list needs nss, which isn't safe after forking, so look it up now
+    let name = CString::new(user.name.as_bytes()).context("user name contains a nul byte")?;
+    let groups = getgrouplist(&name, user.gid)
+        .with_context(|| format!("getting supplementary groups of {}", user.name))?;
+    let (uid, gid) = (user.uid, user.gid);
+    // safety: only async-signal-safe syscalls are made between fork and exec. groups
+    // and gid have to be changed before giving up root with setuid
+    unsafe {
+        command.pre_exec(move || {
+            setgroups(&groups)?;
+            setgid(gid)?;
+            setuid(uid)?;
+            Ok(())
+        });
+    }
+
+    Ok(command)
+}
+
+/// a flag set when a deploy is superseded, stopping the commands it runs
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// when the commands run by this thread have to stop
+#[derive(Debug, Clone, Default)]
+struct Limits {
+    deadline: Option<Instant>,
+    cancel: Option<Cancel>,
+}
+
+thread_local! {
+    static LIMITS: RefCell<Limits> = RefCell::default();
+    static CAPTURE: RefCell<Option<Arc<Mutex<Output>>>> = RefCell::default();
+}
+
+/// a line written by a command
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OutputLine {
+    /// name of the command
+    pub command: String,
+    /// `stdout` or `stderr`
+    pub stream: String,
+    pub line: String,
+}
+
+/// the newest lines written by a deploy's commands, up to `MAX_OUTPUT` bytes
+#[derive(Debug, Default)]
+pub struct Output {
+    pub lines: VecDeque<OutputLine>,
+    /// number of older lines dropped to stay under the limit
+    pub dropped: usize,
+    size: usize,
+}
+
+impl OutputLine {
+    /// roughly how many bytes the line takes up in the history
+    fn size(&self) -> usize {
+        self.command.len() + self.stream.len() + self.line.len() + 40
+    }
+}
+
+impl Output {
+    fn push(&mut self, line: OutputLine) {
+        self.size += line.size();
+        self.lines.push_back(line);
+        while self.size > MAX_OUTPUT {
+            let Some(old) = self.lines.pop_front() else {
+                break;
+            };
+            self.size -= old.size();
+            self.dropped += 1;
+        }
+    }
+}
+
+/// run `f`, keeping the output of the commands it runs on this thread
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, Output) {
+    let output = Arc::new(Mutex::new(Output::default()));
+    let outer = CAPTURE.with(|c| c.replace(Some(output.clone())));
+    let result = f();
+    CAPTURE.with(|c| c.replace(outer));
+
+    // the readers of a command left running in the background can still hold it
+    let output = Arc::try_unwrap(output)
+        .map(|output| output.into_inner().unwrap_or_else(PoisonError::into_inner))
+        .unwrap_or_else(|output| {
+            let mut output = output.lock().unwrap_or_else(PoisonError::into_inner);
+            std::mem::take(&mut *output)
+        });
+    (result, output)
+}
+
+/// run `f` with the commands it runs on this thread killed once `cancel` is set
+pub fn with_cancel<T>(cancel: &Cancel, f: impl FnOnce() -> T) -> T {
+    let limits = Limits {
+        cancel: Some(cancel.clone()),
+        ..LIMITS.with(|l| l.borrow().clone())
+    };
+    scoped(limits, f)
+}
+
+/// run `f` with the commands it runs on this thread killed once `timeout` runs
+/// out, or an outer timeout does. fails right away if the commands were
+/// already stopped
+pub fn with_timeout<T>(
+    timeout: Option<Duration>,
+    f: impl FnOnce() -> color_eyre::Result<T>,
+) -> color_eyre::Result<T> {
+    check_stopped()?;
+    let mut limits = LIMITS.with(|l| l.borrow().clone());
+    if let Some(timeout) = timeout {
+        let deadline = Instant::now() + timeout;
+        limits.deadline = Some(limits.deadline.map_or(deadline, |d| d.min(deadline)));
+    }
+    scoped(limits, f)
+}
+
+/// run `f` with `limits`, restoring the outer ones afterwards
+fn scoped<T>(limits: Limits, f: impl FnOnce() -> T) -> T {
+    let outer = LIMITS.with(|l| l.replace(limits));
+    let result = f();
+    LIMITS.with(|l| l.replace(outer));
+    result
+}
+
+/// why the commands of this thread have to stop, if they do
+pub fn stopped() -> Option<&'static str> {
+    LIMITS.with(|l| {
+        if cancelled() {
+            Some("cancelled")
+        } else if l.borrow().deadline.is_some_and(|d| Instant::now() >= d) {
+            Some("timed out")
+        } else {
+            None
+        }
+    })
+}
+
+/// check if the deploy this thread is running was cancelled
+pub fn cancelled() -> bool {
+    LIMITS.with(|l| l.borrow().cancel.as_ref().is_some_and(Cancel::is_cancelled))
+}
+
+/// fail if the commands of this thread have to stop
+pub fn check_stopped() -> color_eyre::Result<()> {
+    match stopped() {
+        Some(reason) => Err(eyre!("deploy {}", reason)),
+        None => Ok(()),
+    }
+}
+
+/// run a command to completion, logging its output line by line as it's
+/// written. `name` is used to describe the command in logs and errors
+pub fn run(command: &mut Command, name: &str) -> color_eyre::Result<()> {
+    let status = status(command, name)?;
+    check_status(status, name)
+}
+
+/// run a command to completion like `run`, returning how it exited instead of
+/// failing if it wasn't successful
+pub fn status(command: &mut Command, name: &str) -> color_eyre::Result<ExitStatus> {
+    let mut handle = spawn(command, name)?;
+
+    // both pipes have to be drained at once, or the command blocks when one fills up
+    let stdout = handle
+        .stdout
+        .take()
+        .map(|pipe| log_lines(pipe, "stdout", name));
+    let stderr = handle
+        .stderr
+        .take()
+        .map(|pipe| log_lines(pipe, "stderr", name));
+    let status = wait(&mut handle, name);
+    join_readers(stdout.into_iter().chain(stderr));
+
+    status
+}
+
+/// start a command in its own process group with its output piped, after
+/// checking the thread's commands don't have to stop
+fn spawn(command: &mut Command, name: &str) -> color_eyre::Result<Child> {
+    check_stopped()?;
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .with_context(|| format!("could not spawn {name}"))
+}
+
+/// wait for a command to exit, stopping its whole process group if the thread's
+/// commands have to stop first
+fn wait(child: &mut Child, name: &str) -> color_eyre::Result<ExitStatus> {
+    loop {
+        if let Some(status) = child
+            .try_wait()
+            .with_context(|| format!("error waiting for {name}"))?
+        {
+            return Ok(status);
+        }
+        if let Some(reason) = stopped() {
+            tracing::error!("{} {}, stopping it", name, reason);
+            stop(child, name);
+            return Err(eyre!("{} {}", name, reason));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// stop a command's process group with SIGTERM, so e.g. git removes its lock
+/// files, and kill whatever is left of it once the command exits or
+/// `STOP_GRACE` runs out
+fn stop(child: &mut Child, name: &str) {
+    // the process group id is the command's pid
+    let group = Pid::from_raw(child.id() as i32);
+    let _ = killpg(group, Signal::SIGTERM);
+    let deadline = Instant::now() + STOP_GRACE;
+    let exited = loop {
+        if let Ok(Some(_)) = child.try_wait() {
+            break true;
+        }
+        if Instant::now() >= deadline {
+            break false;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    if !exited {
+        tracing::warn!("{} didn't exit after SIGTERM, killing it", name);
+    }
+    let _ = killpg(group, Signal::SIGKILL);
+    let _ = child.wait();
+}
+
+/// wait for the threads reading a command's output. a background process
+/// started by the command can keep the pipes open, so they're only waited on
+/// for a moment
+fn join_readers<T>(readers: impl Iterator<Item = JoinHandle<T>>) -> Vec<Option<T>> {
+    let deadline = Instant::now() + OUTPUT_GRACE;
+    readers
+        .map(|reader| {
+            while !reader.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            if reader.is_finished() {
+                reader.join().ok()
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// log each line read from a pipe on its own thread, in the current span, and
+/// keep it if the thread's output is captured
+fn log_lines(
+    pipe: impl Read + Send + 'static,
+    stream: &'static str,
+    command: &str,
+) -> JoinHandle<()> {
+    let span = Span::current();
+    let capture = CAPTURE.with(|c| c.borrow().clone());
+    let command = command.to_string();
+    thread::spawn(move || {
+        let _entered = span.enter();
+        let mut reader = BufReader::new(pipe);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            // a command can write any amount without a newline, so long lines
+            // are split instead of buffered
+            match (&mut reader).take(MAX_LINE).read_until(b'\n', &mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!("failed to read {stream}: {e}");
+                    break;
+                }
+            }
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end_matches(['\n', '\r']);
+            tracing::info!(stream, command, "{}", line);
+            keep(&capture, &command, stream, line);
+        }
+    })
+}
+
+/// add a line to captured output
+fn keep(capture: &Option<Arc<Mutex<Output>>>, command: &str, stream: &str, line: &str) {
+    if let Some(capture) = capture {
+        capture
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(OutputLine {
+                command: command.to_string(),
+                stream: stream.to_string(),
+                line: line.to_string(),
+            });
+    }
+}
+
+/// read all of a pipe on its own thread
+fn read_all(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// log how a command exited, failing if it wasn't successful
+fn check_status(status: ExitStatus, name: &str) -> color_eyre::Result<()> {
+    tracing::info!(
+        "{} finished with exit code {:?}",
+        name,
+        status
+            .code()
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "<terminated by signal>".to_string())
+    );
+    if status.code().unwrap_or(1) != 0 {
+        tracing::error!("{} finished with error", name);
+        return Err(eyre!("{} finished with non zero exit code", name));
+    }
+    Ok(())
+}
+
+/// run a command to completion and return its stdout, logging its output only
+/// if it fails
+pub fn output(command: &mut Command, name: &str) -> color_eyre::Result<String> {
+    let mut handle = spawn(command, name)?;
+
+    let stdout = handle.stdout.take().map(read_all);
+    let stderr = handle.stderr.take().map(read_all);
+    let status = wait(&mut handle, name);
+    let mut output = join_readers(stdout.into_iter().chain(stderr))
+        .into_iter()
+        .map(|buf| String::from_utf8_lossy(&buf.unwrap_or_default()).into_owned());
+    let (stdout, stderr) = (
+        output.next().unwrap_or_default(),
+        output.next().unwrap_or_default(),
+    );
+
+    if let Err(e) = status.and_then(|status| check_status(status, name)) {
+        tracing::error!("stdout: {}", stdout);
+        tracing::error!("stderr: {}", stderr);
+        let capture = CAPTURE.with(|c| c.borrow().clone());
+        for (stream, out) in [("stdout", &stdout), ("stderr", &stderr)] {
+            for line in out.lines() {
+                keep(&capture, name, stream, line);
+            }
+        }
+        return Err(e);
+    }
+
+    Ok(stdout)
+}
@@ -0,0 +1,228 @@
+//! release directories: each deployed commit is checked out to
+//! `releases/<sha>` and a `current` symlink is switched to it, so the service
+//! never sees half updated files and old releases can be switched back to
+use std::{
+    cmp::Reverse,
+    ffi::OsString,
+    fs::{self, File},
+    io::ErrorKind,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use color_eyre::eyre::{eyre, Context};
+use nix::unistd::{chown, fchownat, FchownatFlags, User};
+use tracing::instrument;
+
+use crate::{
+    config::{ReleasesConfig, Target},
+    git,
+};
+
+/// file written to a release once it's built, releases without it were
+/// interrupted and are checked out again
+const BUILT_MARKER: &str = ".shook-built";
+
+/// a commit checked out to its release directory
+#[derive(Debug, Clone)]
+pub struct Release {
+    /// the release directory, `releases/<sha>`
+    pub dir: PathBuf,
+    /// set if the directory was just checked out and still has to be built
+    pub is_new: bool,
+}
+
+/// make sure the releases config is usable when shook starts
+pub fn check(config: &ReleasesConfig) -> color_eyre::Result<()> {
+    if config.keep == 0 {
+        return Err(eyre!("releases.keep has to be at least 1"));
+    }
+    if !config.path.is_absolute() {
+        return Err(eyre!("releases.path has to be absolute"));
+    }
+    Ok(())
+}
+
+/// the directory holding every release
+fn releases_dir(config: &ReleasesConfig) -> PathBuf {
+    config.path.join("releases")
+}
+
+/// the symlink pointing at the live release
+fn current_link(config: &ReleasesConfig) -> PathBuf {
+    config.path.join("current")
+}
+
+/// the release `current` points at, if any
+pub fn current(config: &ReleasesConfig) -> Option<PathBuf> {
+    let link = fs::read_link(current_link(config)).ok()?;
+    Some(config.path.join(link))
+}
+
+/// the commit of the live release, if there is one
+pub fn live_sha(config: &ReleasesConfig) -> Option<String> {
+    let dir = current(config)?;
+    Some(dir.file_name()?.to_str()?.to_string())
+}
+
+/// check out `sha` to its release directory. an existing release of the
+/// commit is reused if it was built, and checked out again if it wasn't
+#[instrument(skip_all)]
+pub fn create(
+    target: &Target,
+    config: &ReleasesConfig,
+    user: &User,
+    sha: &str,
+) -> color_eyre::Result<Release> {
+    let releases = releases_dir(config);
+    let dir = releases.join(sha);
+    if dir.join(BUILT_MARKER).exists() {
+        tracing::info!("reusing release {:?}", dir);
+        return Ok(Release { dir, is_new: false });
+    }
+    if dir.exists() {
+        // e.g. shook was stopped while checking out or building it
+        tracing::warn!("release {:?} wasn't finished, checking it out again", dir);
+        let unfinished = Release {
+            dir: dir.clone(),
+            is_new: false,
+        };
+        remove(target, user, &unfinished);
+        if dir.exists() {
+            return Err(eyre!("couldn't remove unfinished release {dir:?}"));
+        }
+    }
+
+    if !releases.exists() {
+        fs::create_dir_all(&releases)
+            .with_context(|| format!("creating releases directory {releases:?}"))?;
+        // git runs as the user, so it has to be able to add releases
+        chown(&releases, Some(user.uid), Some(user.gid))
+            .context("changing releases directory owner")?;
+    }
+
+    let release = Release { dir, is_new: true };
+    let checked_out = git::add_worktree(target, user, &release.dir, sha)
+        .and_then(|()| git::checkout_extras(target, user, &release.dir));
+    if let Err(e) = checked_out {
+        remove(target, user, &release);
+        return Err(e);
+    }
+
+    Ok(release)
+}
+
+/// record that a release was built, so it can be reused
+pub fn mark_built(release: &Release) -> color_eyre::Result<()> {
+    let marker = release.dir.join(BUILT_MARKER);
+    File::create(&marker).with_context(|| format!("creating {marker:?}"))?;
+    Ok(())
+}
+
+/// remove a release that failed to build, so it's checked out again next time
+pub fn remove(target: &Target, user: &User, release: &Release) {
+    let Err(e) = git::remove_worktree(target, user, &release.dir) else {
+        return;
+    };
+    // a checkout that was stopped early may not be a worktree git knows about
+    tracing::warn!("git couldn't remove {:?}, deleting it: {e}", release.dir);
+    match fs::remove_dir_all(&release.dir) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            tracing::error!("failed to remove release {:?}: {e}", release.dir);
+        }
+        _ => {}
+    }
+    if let Err(e) = git::prune_worktrees(target, user) {
+        tracing::error!("failed to prune worktrees: {e}");
+    }
+}
+
+/// atomically point `current` at a release
+#[instrument(skip_all)]
+pub fn activate(config: &ReleasesConfig, user: &User, release: &Release) -> color_eyre::Result<()> {
+    let link = current_link(config);
+    // relative, so the releases can be moved along with their directory
+    let relative = release
+        .dir
+        .strip_prefix(&config.path)
+        .context("release isn't in the releases directory")?;
+    replace_symlink(&link, relative, Some(user))?;
+
+    // releases are pruned oldest first, and a reused release is the newest
+    File::open(&release.dir)
+        .and_then(|dir| dir.set_modified(SystemTime::now()))
+        .with_context(|| format!("updating modification time of {:?}", release.dir))?;
+    tracing::info!("switched {:?} to {:?}", link, relative);
+
+    Ok(())
+}
+
+/// atomically point a symlink at `to`, creating it if it doesn't exist.
+/// `owner` owns the new symlink
+pub fn replace_symlink(link: &Path, to: &Path, owner: Option<&User>) -> color_eyre::Result<()> {
+    let name = link
+        .file_name()
+        .ok_or_else(|| eyre!("{link:?} isn't a file path"))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(".tmp");
+    let tmp = link.with_file_name(tmp_name);
+
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("removing {tmp:?}"));
+        }
+        _ => {}
+    }
+    symlink(to, &tmp).with_context(|| format!("creating symlink {tmp:?}"))?;
+    if let Some(owner) = owner {
+        fchownat(
+            None,
+            &tmp,
+            Some(owner.uid),
+            Some(owner.gid),
+            FchownatFlags::NoFollowSymlink,
+        )
+        .context("changing symlink owner")?;
+    }
+    // rename replaces the old symlink in one step, there's never a moment
+    // without it
+    fs::rename(&tmp, link).with_context(|| format!("replacing {link:?}"))
+}
+
+/// every release, newest first
+pub fn list(config: &ReleasesConfig) -> color_eyre::Result<Vec<PathBuf>> {
+    let releases = releases_dir(config);
+    let entries = match fs::read_dir(&releases) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {releases:?}")),
+    };
+
+    let mut releases = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            releases.push((metadata.modified()?, entry.path()));
+        }
+    }
+    releases.sort_by_key(|(modified, _)| Reverse(*modified));
+
+    Ok(releases.into_iter().map(|(_, path)| path).collect())
+}
+
+/// remove all but the newest `keep` releases, never removing the live one
+#[instrument(skip_all)]
+pub fn prune(target: &Target, config: &ReleasesConfig, user: &User) -> color_eyre::Result<()> {
+    let current = current(config);
+    for dir in list(config)?.into_iter().skip(config.keep) {
+        if Some(&dir) == current.as_ref() {
+            continue;
+        }
+        remove(target, user, &Release { dir, is_new: false });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use color_eyre::eyre::eyre;
+use uuid::Uuid;
+
+use crate::{
+    config::{Rollback, ServerConfig, Target},
+    history::{self, Deployment},
+    server::{self, DeployKind, DeployRequest},
+};
+
+/// check out an earlier commit and run the rest of the deploy pipeline with it
+pub fn rollback(args: Rollback) -> color_eyre::Result<()> {
+    let config = ServerConfig::load(args.repo_path.as_deref())?;
+    let name = args.target.as_deref();
+    let target = config
+        .target(name)
+        .ok_or_else(|| eyre!("unknown target: {}", name.unwrap_or_default()))?;
+
+    let sha = match (args.sha, args.steps) {
+        (Some(_), Some(_)) => return Err(eyre!("--sha and --steps can't be used together")),
+        (Some(sha), None) => sha,
+        (None, steps) => deployed_ago(target, name, steps.unwrap_or(1))?,
+    };
+    tracing::info!("rolling back to {}", sha);
+
+    server::deploy(
+        target,
+        &config.timeouts,
+        &DeployRequest {
+            guid: Uuid::new_v4(),
+            target: args.target.clone(),
+            kind: DeployKind::Rollback,
+            sha: Some(sha),
+            forced: false,
+            dry_run: false,
+        },
+    )
+    .map_err(|_| eyre!("rollback failed"))
+}
+
+/// the commit deployed `steps` deploys before the live one. rollbacks don't
+/// count, so rolling back again goes further back
+fn deployed_ago(target: &Target, name: Option<&str>, steps: usize) -> color_eyre::Result<String> {
+    commit_before(&history::read(target, name)?, steps)
+}
+
+/// the commit `steps` commits before the live one in a deploy history, oldest
+/// first
+fn commit_before(deployments: &[Deployment], steps: usize) -> color_eyre::Result<String> {
+    let successful = || deployments.iter().rev().filter(|d| d.success);
+    let live = successful()
+        .find_map(|d| d.sha.as_deref())
+        .ok_or_else(|| eyre!("the deploy history has no successful deploys"))?;
+
+    // newest first, a commit deployed more than once counts where it was last deployed
+    let mut commits: Vec<&str> = Vec::new();
+    for sha in successful()
+        .filter(|d| d.kind == DeployKind::Update)
+        .filter_map(|d| d.sha.as_deref())
+    {
+        if !commits.contains(&sha) {
+            commits.push(sha);
+        }
+    }
+
+    // the live commit is missing if it was deployed with `--skip-pull`, every
+    // other deploy came before it then
+    let earlier = match commits.iter().position(|sha| *sha == live) {
+        Some(live) => &commits[live + 1..],
+        None => &commits[..],
+    };
+    match steps.checked_sub(1) {
+        None => Ok(live.to_string()),
+        Some(back) => earlier.get(back).map(ToString::to_string).ok_or_else(|| {
+            eyre!(
+                "the deploy history only has {} commits before the live one",
+                earlier.len()
+            )
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment(kind: DeployKind, sha: &str, success: bool) -> Deployment {
+        Deployment {
+            guid: Uuid::new_v4(),
+            target: None,
+            kind,
+            time: Deployment::now(),
+            previous: None,
+            sha: Some(sha.to_string()),
+            success,
+            cancelled: false,
+            output: Default::default(),
+            output_dropped: 0,
+        }
+    }
+
+    #[test]
+    fn counts_back_from_the_live_commit() {
+        let history = [
+            deployment(DeployKind::Update, "a", true),
+            deployment(DeployKind::Update, "b", true),
+            deployment(DeployKind::Update, "c", true),
+            deployment(DeployKind::Update, "d", false),
+            deployment(DeployKind::Rollback, "b", true),
+        ];
+        assert_eq!(commit_before(&history, 0).unwrap(), "b");
+        assert_eq!(commit_before(&history, 1).unwrap(), "a");
+        assert!(commit_before(&history, 2).is_err());
+    }
+
+    #[test]
+    fn live_commit_only_checked_out() {
+        let history = [
+            deployment(DeployKind::Update, "a", true),
+            deployment(DeployKind::Update, "b", true),
+            deployment(DeployKind::CheckedOut, "local", true),
+        ];
+        assert_eq!(commit_before(&history, 1).unwrap(), "b");
+        assert_eq!(commit_before(&history, 2).unwrap(), "a");
+        assert!(commit_before(&history, 3).is_err());
+    }
+}
@@ -1,75 +1,147 @@
 use std::{
-    io::Read, os::fd::AsRawFd, path::{Path, PathBuf}, pin::Pin, process::{Command, Stdio}, task::{Context, Poll}
+    collections::{HashMap, HashSet},
+    fs::Permissions,
+    io::ErrorKind,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll},
 };
 
-use axum::{debug_handler, extract::State, routing::post, Router};
+use axum::{
+    body::Bytes, debug_handler, extract::State, http::HeaderMap, routing::post, Extension, Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
 use color_eyre::eyre::{eyre, Context as _};
 use futures::ready;
-use github_webhook_extract::GithubPayload;
+use github_webhook_extract::EventDiscriminants;
 use hyper::{server::accept::Accept, StatusCode};
-use nix::{
-    sys::stat::{fchmod, Mode},
-    unistd::{chown, Gid, Uid},
-};
+use nix::unistd::{chown, User};
+use serde::{Deserialize, Serialize};
 use tokio::{
-    fs::{self, File},
-    io::AsyncReadExt,
+    fs,
     net::{UnixListener, UnixStream},
 };
 use tower_http::{trace::TraceLayer, BoxError};
-use tracing::instrument;
-
-use crate::config::{Serve, ServerConfig, TcpOrUnix};
+use tracing::{instrument, Span};
+use uuid::Uuid;
+
+use crate::{
+    blue_green::{self, Color},
+    config::{
+        ListenConfig, ReleasesConfig, Serve, ServerConfig, Stage, Target, TcpOrUnix, Timeouts,
+    },
+    deploy_hook, git,
+    history::{self, Deployment},
+    lock, pipeline,
+    process::{self, Cancel},
+    release, systemd, tls, units, user,
+    webhook::Delivery,
+};
 
 pub async fn serve(args: Serve) -> color_eyre::Result<()> {
     tracing::info!("serving project");
 
-    let mut config: ServerConfig = {
-        let config_path = args
-            .repo_path
-            .clone()
-            .unwrap_or_else(|| PathBuf::from("."))
-            .join("shook.toml");
-        let mut file = File::open(&config_path)
-            .await
-            .context("opening shook config")?;
-        let mut buf = String::new();
-        file.read_to_string(&mut buf)
-            .await
-            .context("reading shook config")?;
-        toml::from_str(&buf).context("parsing shook config")?
-    };
+    let mut config = ServerConfig::load(args.repo_path.as_deref())?;
     config.merge(args);
+    for (name, target) in config.all_targets() {
+        let name = name.unwrap_or("top level target");
+        let user =
+            user::user(&target.username).with_context(|| format!("finding user for {name}"))?;
+        git::check(target, &user).with_context(|| format!("checking git backend for {name}"))?;
+        if let Some(releases) = &target.releases {
+            release::check(releases).with_context(|| format!("checking releases for {name}"))?;
+        }
+        pipeline::check(target).with_context(|| format!("checking steps for {name}"))?;
+        units::check(&target.systemd())
+            .with_context(|| format!("checking systemd backend for {name}"))?;
+        if let Some(blue_green) = &target.blue_green {
+            blue_green::check(target, blue_green)
+                .with_context(|| format!("checking blue_green for {name}"))?;
+        }
+    }
 
-    let app = Router::new()
-        .route("/", post(handler))
+    let mut router = Router::new();
+    let mut paths = HashSet::new();
+    for (name, path) in config.webhook_paths() {
+        if !path.starts_with('/') || !paths.insert(path) {
+            return Err(eyre!("webhook path is invalid or used twice: {path}"));
+        }
+        tracing::info!(
+            "receiving webhooks for {} on {}",
+            name.unwrap_or("top level target"),
+            path
+        );
+        let name = TargetName(name.map(ToString::to_string));
+        router = router.route(path, post(handler).layer(Extension(name)));
+    }
+    if let Some(hook) = &config.deploy_hook {
+        if !hook.path.starts_with('/') || !paths.insert(&hook.path) {
+            return Err(eyre!(
+                "deploy hook path is invalid or used twice: {}",
+                hook.path
+            ));
+        }
+        tracing::info!("enabling deploy hook on {}", hook.path);
+        router = router.route(&hook.path, post(deploy_hook_handler));
+    }
+    let app = router
         .layer(TraceLayer::new_for_http())
         .with_state(AppState {
             config: config.clone(),
+            deploys: Arc::default(),
         });
 
-    tracing::info!("serving on {}", config.addr.to_string());
-    match config.addr {
-        TcpOrUnix::Unix(path) => {
-            let _ = fs::remove_file(&path).await;
-            fs::create_dir_all(path.parent().unwrap()).await.unwrap();
-
-            let uds = UnixListener::bind(path.clone()).unwrap();
-            chown(
-                &path,
-                Some(user_id(&config.socket_user).await?),
-                Some(group_id(&config.socket_group).await?),
-            )
-            .context("changing socket owner and group")?;
-            fchmod(uds.as_raw_fd(), Mode::from_bits(0o666).unwrap())
-                .context("changing socket permissions")?;
+    let mut listeners = systemd::listen_fds().context("taking sockets from systemd")?;
+    if listeners.is_empty() {
+        for listen in config.listen_addrs() {
+            tracing::info!("serving on {}", listen.addr.to_string());
+            listeners.push(bind(&listen).await?);
+        }
+    } else {
+        tracing::info!("serving on {} sockets passed by systemd", listeners.len());
+    }
+
+    let has_tcp = listeners.iter().any(|l| matches!(l, Listener::Tcp(_)));
+    let tls = match &config.tls {
+        Some(tls) if has_tcp => Some((tls::load(tls).await?, tls.clone())),
+        Some(_) => {
+            tracing::warn!("tls is only used when serving over tcp");
+            None
+        }
+        None => None,
+    };
+    let rustls = tls.as_ref().map(|(rustls, _)| rustls.clone());
+    tls::reload_on_hangup(tls)?;
+
+    let servers = listeners
+        .into_iter()
+        .map(|listener| serve_listener(listener, rustls.clone(), app.clone()));
+    futures::future::try_join_all(servers).await?;
+
+    Ok(())
+}
 
+/// serve the app on one socket, using tls for tcp sockets if it's configured
+async fn serve_listener(
+    listener: Listener,
+    rustls: Option<RustlsConfig>,
+    app: Router,
+) -> color_eyre::Result<()> {
+    match (listener, rustls) {
+        (Listener::Unix(uds), _) => {
             axum::Server::builder(ServerAccept { uds })
                 .serve(app.into_make_service())
                 .await?;
         }
-        TcpOrUnix::Tcp(socket) => {
-            axum::Server::bind(&socket)
+        (Listener::Tcp(tcp), Some(rustls)) => {
+            axum_server::from_tcp_rustls(tcp, rustls)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        (Listener::Tcp(tcp), None) => {
+            axum::Server::from_tcp(tcp)?
                 .serve(app.into_make_service())
                 .await?;
         }
@@ -78,265 +150,620 @@ pub async fn serve(args: Serve) -> color_eyre::Result<()> {
     Ok(())
 }
 
+/// a socket accepting connections
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    Unix(UnixListener),
+}
+
+/// bind to an address in the config
+async fn bind(listen: &ListenConfig) -> color_eyre::Result<Listener> {
+    match &listen.addr {
+        TcpOrUnix::Unix(path) => {
+            if listen.socket_mode & !0o777 != 0 {
+                return Err(eyre!("invalid socket mode: {:o}", listen.socket_mode));
+            }
+            remove_stale_socket(path).await?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .await
+                    .with_context(|| format!("creating socket directory {parent:?}"))?;
+            }
+
+            let uds = UnixListener::bind(path)
+                .with_context(|| format!("binding unix socket {path:?}"))?;
+            let user = listen
+                .socket_user
+                .as_deref()
+                .map(user::user_id)
+                .transpose()?;
+            let group = listen
+                .socket_group
+                .as_deref()
+                .map(user::group_id)
+                .transpose()?;
+            chown(path, user, group).context("changing socket owner and group")?;
+            // fchmod on the socket's fd doesn't change the permissions of its path
+            fs::set_permissions(path, Permissions::from_mode(listen.socket_mode))
+                .await
+                .context("changing socket permissions")?;
+
+            Ok(Listener::Unix(uds))
+        }
+        TcpOrUnix::Tcp(socket) => {
+            let tcp = std::net::TcpListener::bind(socket).context("binding tcp socket")?;
+            tcp.set_nonblocking(true)?;
+
+            Ok(Listener::Tcp(tcp))
+        }
+    }
+}
+
+/// remove a socket left behind by a previous shook. fails if the path isn't a
+/// socket or another process is still accepting connections on it
+async fn remove_stale_socket(path: &Path) -> color_eyre::Result<()> {
+    let metadata = match fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("checking socket path {path:?}")),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(eyre!(
+            "{path:?} exists and isn't a socket, refusing to remove it"
+        ));
+    }
+
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(eyre!(
+            "{path:?} is accepting connections, is another shook instance running?"
+        )),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            tracing::info!("removing stale socket {:?}", path);
+            fs::remove_file(path)
+                .await
+                .with_context(|| format!("removing stale socket {path:?}"))
+        }
+        Err(e) => Err(e).with_context(|| format!("checking if {path:?} is in use")),
+    }
+}
+
 /// state of the app
 #[derive(Clone)]
 pub struct AppState {
     pub config: ServerConfig,
+    pub deploys: Arc<Deploys>,
+}
+
+/// the deploys of each target, so a newer deploy cancels the one it supersedes
+/// instead of waiting for it
+#[derive(Debug, Default)]
+pub struct Deploys {
+    targets: Mutex<HashMap<Option<String>, Arc<TargetDeploys>>>,
+}
+
+#[derive(Debug, Default)]
+struct TargetDeploys {
+    /// held while a deploy of the target runs
+    running: Mutex<()>,
+    /// cancels the newest deploy of the target
+    latest: Mutex<Cancel>,
+}
+
+impl Deploys {
+    /// cancel the target's deploy in progress (or waiting to start), then run
+    /// `deploy` once it has stopped
+    fn supersede<T>(&self, target: Option<&str>, deploy: impl FnOnce(&Cancel) -> T) -> T {
+        let deploys = lock(&self.targets)
+            .entry(target.map(ToString::to_string))
+            .or_default()
+            .clone();
+        let cancel = Cancel::default();
+        let previous = std::mem::replace(&mut *lock(&deploys.latest), cancel.clone());
+        previous.cancel();
+
+        let _running = lock(&deploys.running);
+        deploy(&cancel)
+    }
 }
 
+/// lock a mutex, ignoring poisoning since the data is still usable
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// run a deploy on a blocking thread, superseding the target's deploy in progress
+async fn spawn_deploy(
+    state: AppState,
+    target: Target,
+    request: DeployRequest,
+) -> Result<(), StatusCode> {
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        span.in_scope(|| {
+            state
+                .deploys
+                .supersede(request.target.as_deref(), |cancel| {
+                    process::with_cancel(cancel, || {
+                        deploy(&target, &state.config.timeouts, &request)
+                    })
+                })
+        })
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("deploy panicked: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?
+}
+
+/// name of the target a webhook route deploys, `None` is the top level target
+#[derive(Debug, Clone)]
+struct TargetName(Option<String>);
+
 #[debug_handler]
-#[instrument(skip_all)]
-async fn handler(State(state): State<AppState>, payload: GithubPayload) -> Result<(), StatusCode> {
-    let event = payload.event.into();
+#[instrument(skip_all, fields(target = ?name))]
+async fn handler(
+    State(state): State<AppState>,
+    Extension(TargetName(name)): Extension<TargetName>,
+    delivery: Delivery,
+) -> Result<(), StatusCode> {
+    tracing::debug!(guid = %delivery.guid, provider = ?delivery.provider);
+    if !state.config.providers.contains(&delivery.provider) {
+        tracing::warn!("provider not enabled: {:?}", delivery.provider);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let target = state
+        .config
+        .target(name.as_deref())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let event = delivery.event;
     if !state.config.update_events.contains(&event) {
         tracing::debug!("event not matched: {:?}", &event);
         return Ok(());
     }
+    let update = delivery.branch_update(&target.branch);
+    if event == EventDiscriminants::Push && update.is_none() {
+        tracing::debug!("push did not update {}", target.branch);
+        return Ok(());
+    }
+    if update.is_some_and(|u| u.is_deleted()) {
+        tracing::warn!("push deleted {}, not deploying", target.branch);
+        return Ok(());
+    }
     tracing::debug!("event matched");
 
-    // pull repository
-    pull_updates(&state).map_err(|e| {
-        tracing::error!("failed to pull changes: {e}");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    spawn_deploy(
+        state.clone(),
+        target.clone(),
+        DeployRequest {
+            guid: delivery.guid,
+            target: name,
+            kind: DeployKind::Update,
+            sha: update.map(|u| u.after.clone()),
+            forced: update.is_some_and(|u| u.forced),
+            dry_run: false,
+        },
+    )
+    .await
+}
 
-    pre_restart(&state).map_err(|e| {
-        tracing::error!("failed to run pre-restart command: {e}");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+/// deploy a commit when a custom ci system asks for it
+#[debug_handler]
+#[instrument(skip_all)]
+async fn deploy_hook_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(), StatusCode> {
+    let hook = state
+        .config
+        .deploy_hook
+        .as_ref()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let payload = deploy_hook::verify(hook, &headers, &body)?;
+    tracing::debug!(?payload);
+
+    let target = state
+        .config
+        .target(payload.target.as_deref())
+        .ok_or_else(|| {
+            tracing::warn!("unknown target: {:?}", payload.target);
+            StatusCode::NOT_FOUND
+        })?;
+    if !payload.matches_branch(&target.branch) {
+        tracing::warn!(
+            "{:?} is not the tracked branch {}",
+            payload.r#ref,
+            target.branch
+        );
+        return Err(StatusCode::CONFLICT);
+    }
 
-    restart_service(&state).map_err(|e| {
-        tracing::error!("failed to restart service: {e}");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    spawn_deploy(
+        state.clone(),
+        target.clone(),
+        DeployRequest {
+            guid: Uuid::new_v4(),
+            target: payload.target,
+            kind: DeployKind::Update,
+            sha: Some(payload.sha),
+            forced: false,
+            dry_run: false,
+        },
+    )
+    .await
+}
 
-    tracing::info!("restarted system");
+/// a request to deploy a target
+#[derive(Debug, Clone)]
+pub struct DeployRequest {
+    /// id of the webhook delivery, or a random id for deploy hook requests and
+    /// commands
+    pub guid: Uuid,
+    /// name of the target, `None` is the top level target
+    pub target: Option<String>,
+    pub kind: DeployKind,
+    /// commit that should be deployed, if known
+    pub sha: Option<String>,
+    /// set if the webhook reported a force push
+    pub forced: bool,
+    /// only log what each stage would do
+    pub dry_run: bool,
+}
 
-    Ok(())
+/// how the commit to deploy is checked out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployKind {
+    /// fetch the branch and apply `sha` (or the tip of the branch)
+    Update,
+    /// check out `sha` from an earlier deploy without fetching
+    Rollback,
+    /// deploy the commit that's already checked out
+    CheckedOut,
 }
 
-/// pull code updates
-#[instrument(skip_all)]
-fn pull_updates(state: &AppState) -> color_eyre::Result<()> {
-    tracing::info!(
-        "pulling changes from {} {}",
-        state.config.remote,
-        state.config.branch
-    );
-    let mut handle = Command::new("su")
-        .arg(&state.config.username)
-        .arg("-c")
-        .arg(format!(
-            "git pull '{}' '{}'",
-            state.config.remote, state.config.branch
-        ))
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .current_dir(&state.config.repo_path)
-        .env("GIT_TERMINAL_PROMPT", "0")
-        .spawn()?;
-
-    let status = handle.wait()?;
+/// run each stage of a deployment: pull, pre-restart and restart, then record
+/// it in the deploy history
+#[instrument(skip_all, fields(guid = %request.guid))]
+pub fn deploy(
+    target: &Target,
+    timeouts: &Timeouts,
+    request: &DeployRequest,
+) -> Result<(), StatusCode> {
     tracing::info!(
-        "git finished with exit code {:?}",
-        status
-            .code()
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| "<terminated by signal>".to_string())
+        "deploying {} to {}",
+        request.sha.as_deref().unwrap_or("latest commit"),
+        target.system_name
     );
-    if status.code().unwrap_or(1) != 0 {
-        tracing::error!("git finished with error");
-        let mut buf = String::new();
-        if let Some(mut stdout) = handle.stdout {
-            stdout.read_to_string(&mut buf)?;
-        }
-        tracing::error!("stdout: {}", buf);
-        buf.clear();
-        if let Some(mut stderr) = handle.stderr {
-            stderr.read_to_string(&mut buf)?;
+
+    if request.dry_run {
+        return dry_run(target, request).map_err(|e| {
+            tracing::error!("{e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        });
+    }
+
+    let _lock = lock::lock(target).map_err(|e| {
+        tracing::error!("failed to lock the repository: {e}");
+        match process::cancelled() {
+            true => StatusCode::CONFLICT,
+            false => StatusCode::INTERNAL_SERVER_ERROR,
         }
-        tracing::error!("stderr: {}", buf);
-        return Err(eyre!("git finished with non zero exit code"));
+    })?;
+    let mut update = None;
+    let (result, output) = process::capture(|| run_stages(target, timeouts, request, &mut update));
+    let deployment = Deployment {
+        guid: request.guid,
+        target: request.target.clone(),
+        kind: request.kind,
+        time: Deployment::now(),
+        previous: update.as_ref().map(|u| u.old_head.clone()),
+        sha: update.map(|u| u.new_head).or_else(|| request.sha.clone()),
+        success: result.is_ok(),
+        cancelled: result.is_err() && process::cancelled(),
+        output: output.lines,
+        output_dropped: output.dropped,
+    };
+    if let Err(e) = history::record(target, &deployment) {
+        tracing::error!("failed to record deploy: {e}");
     }
 
-    Ok(())
+    if result.is_err() && process::cancelled() {
+        tracing::warn!("deploy was cancelled by a newer deploy");
+        return Err(StatusCode::CONFLICT);
+    }
+    result
 }
 
-#[instrument(skip_all)]
-fn pre_restart(state: &AppState) -> color_eyre::Result<()> {
-    tracing::info!(
-        "running pre-restart command: {}",
-        state.config.pre_restart_command,
-    );
-    let mut handle = Command::new("su")
-        .arg(&state.config.username)
-        .arg("-c")
-        .arg(&state.config.pre_restart_command)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .current_dir(&state.config.repo_path)
-        .spawn()?;
-
-    let status = handle.wait()?;
+/// the stages of a deployment. `update` is set once the checkout is updated
+fn run_stages(
+    target: &Target,
+    timeouts: &Timeouts,
+    request: &DeployRequest,
+    update: &mut Option<git::Update>,
+) -> Result<(), StatusCode> {
+    let user = user::user(&target.username).map_err(|e| {
+        tracing::error!("failed to find user: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // pull repository
+    let pull = Timeouts::limit(timeouts.pull);
+    let build = Timeouts::limit(timeouts.build);
+    let pulled = process::with_timeout(pull, || pull_updates(target, &user, request));
+    let update = update.insert(pulled.map_err(|e| {
+        tracing::error!("failed to pull changes: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?);
     tracing::info!(
-        "{} finished with exit code {:?}",
-        state.config.pre_restart_command,
-        status
-            .code()
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| "<terminated by signal>".to_string())
+        "updated {} to {}, {} files changed",
+        update.old_head,
+        update.new_head,
+        update.changed_files.len()
     );
-    if status.code().unwrap_or(1) != 0 {
-        tracing::error!("{} finished with error", state.config.pre_restart_command);
-        let mut buf = String::new();
-        if let Some(mut stdout) = handle.stdout {
-            stdout.read_to_string(&mut buf)?;
+    tracing::debug!(changed_files = ?update.changed_files);
+    let changed = known_changes(target, &user, request, update);
+    let changed = changed.as_deref();
+
+    let dir = match &target.releases {
+        Some(releases) => switch_release(target, timeouts, releases, &user, &update.new_head)
+            .map_err(|e| {
+                tracing::error!("failed to switch release: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?,
+        None => {
+            process::with_timeout(build, || {
+                pipeline::run(target, &user, Stage::PreRestart, &target.repo_path, changed)
+            })
+            .map_err(|e| {
+                tracing::error!("failed to run pre-restart steps: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            target.repo_path.clone()
         }
-        tracing::error!("stdout: {}", buf);
-        buf.clear();
-        if let Some(mut stderr) = handle.stderr {
-            stderr.read_to_string(&mut buf)?;
+    };
+
+    process::with_timeout(Timeouts::limit(timeouts.restart), || {
+        restart_services(target, &user, changed)
+    })
+    .map_err(|e| {
+        tracing::error!("failed to restart service: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    process::with_timeout(build, || {
+        pipeline::run(target, &user, Stage::PostRestart, &dir, changed)
+    })
+    .map_err(|e| {
+        tracing::error!("failed to run post-restart steps: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // the deploy already succeeded, old releases are pruned next time
+    if let Some(releases) = &target.releases {
+        if let Err(e) = release::prune(target, releases, &user) {
+            tracing::error!("failed to prune old releases: {e}");
         }
-        tracing::error!("stderr: {}", buf);
-        return Err(eyre!("{} finished with non zero exit code", state.config.pre_restart_command));
     }
 
     Ok(())
 }
 
-/// restart the systemd service which code we are watching
-#[instrument(skip_all)]
-fn restart_service(state: &AppState) -> color_eyre::Result<()> {
-    // restart systemd service
-    let mut handle = Command::new("systemctl")
-        .arg("restart")
-        .arg(&state.config.system_name)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|e| eyre!("could not spawn systemctl: {e}"))?;
-
-    let status = handle
-        .wait()
-        .map_err(|e| eyre!("error waiting for systemctl: {e}"))?;
-    tracing::info!(
-        "systemctl finished with exit code {:?}",
-        status
-            .code()
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| "<terminated by signal>".to_string())
-    );
-    if status.code().unwrap_or(1) != 0 {
-        let mut buf = String::new();
-        if let Some(mut stdout) = handle.stdout {
-            stdout.read_to_string(&mut buf)?;
+/// log what each stage of a deployment would do without changing anything
+fn dry_run(target: &Target, request: &DeployRequest) -> color_eyre::Result<()> {
+    let user = user::user(&target.username).context("failed to find user")?;
+    git::check(target, &user)?;
+
+    let sha = request.sha.as_deref();
+    match request.kind {
+        DeployKind::Update if target.releases.is_some() => tracing::info!(
+            "would fetch {} from {} {}",
+            sha.unwrap_or("the latest commit"),
+            target.remote,
+            target.branch
+        ),
+        DeployKind::Update => tracing::info!(
+            "would pull {} from {} {} with the {:?} strategy",
+            sha.unwrap_or("the latest commit"),
+            target.remote,
+            target.branch,
+            target.update_strategy
+        ),
+        DeployKind::Rollback => tracing::info!(
+            "would check out {}",
+            sha.ok_or_else(|| eyre!("rolling back needs a commit"))?
+        ),
+        DeployKind::CheckedOut => {
+            let live = target.releases.as_ref().and_then(release::live_sha);
+            let sha = match live {
+                Some(live) => live,
+                None => git::current(target, &user)?.new_head,
+            };
+            tracing::info!("would deploy the checked out commit {}", sha);
+        }
+    }
+    if let Some(releases) = &target.releases {
+        tracing::info!("would check out a release in {:?}", releases.path);
+    }
+    // which files will change isn't known until the checkout is updated
+    let when = |paths: &[String]| match paths {
+        [] => String::new(),
+        paths => format!(" if {paths:?} changed"),
+    };
+    let would_run = |stage| {
+        for step in pipeline::steps(target, stage) {
+            let when = when(&step.paths);
+            tracing::info!("would run step {}{}: `{}`", step.name, when, step.command);
         }
-        tracing::error!("stdout: {}", buf);
-        buf.clear();
-        if let Some(mut stderr) = handle.stderr {
-            stderr.read_to_string(&mut buf)?;
+    };
+    would_run(Stage::PreRestart);
+    for (i, unit) in target.units().into_iter().enumerate() {
+        match &target.blue_green {
+            Some(config) if i == 0 => {
+                let idle = blue_green::live(config).map_or(Color::Blue, Color::other);
+                tracing::info!(
+                    "would start {}, health check it and switch {:?} to it{}",
+                    blue_green::instance(&unit.name, idle)?,
+                    config.link,
+                    when(&unit.paths)
+                );
+            }
+            _ => {
+                let action = unit.action.command();
+                tracing::info!("would {} {}{}", action, unit.name, when(&unit.paths));
+            }
         }
-        tracing::error!("stderr: {}", buf);
-        return Err(eyre!("systemctl finished with error"));
     }
+    would_run(Stage::PostRestart);
 
     Ok(())
 }
 
-/// get the group id of a group from /etc/group file
-#[instrument(skip_all)]
-async fn group_id(name: &str) -> color_eyre::Result<Gid> {
-    let groups = fs::read_to_string("/etc/group").await?;
-    #[derive(Debug)]
-    struct Entry<'a> {
-        name: &'a str,
-        _password: &'a str,
-        gid: Gid,
-        _group_list: Vec<&'a str>,
+/// the files a deploy changed, or `None` if everything should run again: when
+/// deploying the checked out commit, or when the last deploy didn't succeed
+/// (or there wasn't one) so its steps may not have run. deploys superseded
+/// since then may have moved the checkout, so the changes are counted from the
+/// last deploy that finished
+fn known_changes(
+    target: &Target,
+    user: &User,
+    request: &DeployRequest,
+    update: &git::Update,
+) -> Option<Vec<String>> {
+    if request.kind == DeployKind::CheckedOut {
+        return None;
     }
+    let (last, newest) = match history::last_finished(target, request.target.as_deref()) {
+        Ok(last) => last?,
+        Err(e) => {
+            tracing::warn!("failed to read deploy history, running every step: {e}");
+            return None;
+        }
+    };
 
-    for line in groups.lines() {
-        let mut parts = line.split(':');
-        let ent = Entry {
-            name: parts
-                .next()
-                .ok_or_else(|| eyre!("error parsing /etc/group: name missing"))?,
-            _password: parts
-                .next()
-                .ok_or_else(|| eyre!("error parsing /etc/group: password missing"))?,
-            gid: parts
-                .next()
-                .and_then(|s| s.parse().ok())
-                .map(Gid::from_raw)
-                .ok_or_else(|| eyre!("error parsing /etc/group: gid missing"))?,
-            _group_list: parts
-                .next()
-                .ok_or_else(|| eyre!("error parsing /etc/group: users missing"))?
-                .split(',')
-                .collect::<Vec<_>>(),
-        };
-        if ent.name == name {
-            tracing::debug!("found group: {:?}", ent);
-            return Ok(ent.gid);
+    if !last.success {
+        tracing::info!("last deploy didn't succeed, running every step");
+        return None;
+    }
+    if newest {
+        return Some(update.changed_files.clone());
+    }
+    let sha = last.sha.as_deref()?;
+    tracing::info!("newer deploys were superseded, counting changes from {sha}");
+    git::changed_files(target, user, sha, &update.new_head)
+        .map_err(|e| tracing::warn!("failed to compare with {sha}, running every step: {e}"))
+        .ok()
+}
+
+/// pull code updates
+#[instrument(skip_all)]
+fn pull_updates(
+    target: &Target,
+    user: &User,
+    request: &DeployRequest,
+) -> color_eyre::Result<git::Update> {
+    if let Some(releases) = &target.releases {
+        return fetch_release(target, releases, user, request);
+    }
+    match (request.kind, &request.sha) {
+        (DeployKind::Update, sha) => {
+            tracing::info!("pulling changes from {} {}", target.remote, target.branch);
+            git::update(target, user, sha.as_deref(), request.forced)
         }
+        (DeployKind::Rollback, Some(sha)) => git::checkout(target, user, sha),
+        (DeployKind::Rollback, None) => Err(eyre!("rolling back needs a commit")),
+        (DeployKind::CheckedOut, _) => git::current(target, user),
     }
+}
 
-    Err(eyre!("entry not found"))
+/// find the commit to deploy to a release without touching `repo_path`'s
+/// checkout, which is only fetched into. what changed is counted from the live
+/// release
+#[instrument(skip_all)]
+fn fetch_release(
+    target: &Target,
+    releases: &ReleasesConfig,
+    user: &User,
+    request: &DeployRequest,
+) -> color_eyre::Result<git::Update> {
+    let live = release::live_sha(releases);
+    let new_head = match (request.kind, &request.sha) {
+        (DeployKind::Update, sha) => {
+            tracing::info!("fetching {} {}", target.remote, target.branch);
+            git::fetch(target, user, sha.as_deref())?
+        }
+        (DeployKind::Rollback, Some(sha)) => git::resolve_commit(target, user, sha)?,
+        (DeployKind::Rollback, None) => return Err(eyre!("rolling back needs a commit")),
+        (DeployKind::CheckedOut, _) => match &live {
+            Some(live) => live.clone(),
+            None => git::current(target, user)?.new_head,
+        },
+    };
+    let old_head = match live {
+        Some(live) => live,
+        None => git::current(target, user)?.new_head,
+    };
+    let changed_files = git::changed_files(target, user, &old_head, &new_head)?;
+
+    Ok(git::Update {
+        old_head,
+        new_head,
+        changed_files,
+    })
 }
 
-/// get the user id of a user from /etc/passwd file
+/// check out a commit to its release directory, build it with the pre-restart
+/// steps and point `current` at it. returns the release directory
 #[instrument(skip_all)]
-async fn user_id(name: &str) -> color_eyre::Result<Uid> {
-    let passwd = fs::read_to_string("/etc/passwd").await?;
-    #[derive(Debug)]
-    struct Entry<'a> {
-        name: &'a str,
-        _password: &'a str,
-        uid: Uid,
-        _gid: Gid,
-        _info: &'a str,
-        _home: &'a Path,
-        _shell: &'a Path,
+fn switch_release(
+    target: &Target,
+    timeouts: &Timeouts,
+    releases: &ReleasesConfig,
+    user: &User,
+    sha: &str,
+) -> color_eyre::Result<PathBuf> {
+    let release = process::with_timeout(Timeouts::limit(timeouts.pull), || {
+        release::create(target, releases, user, sha)
+    })?;
+    if release.is_new {
+        // a new release starts out without any build output, so every step runs
+        let built = process::with_timeout(Timeouts::limit(timeouts.build), || {
+            pipeline::run(target, user, Stage::PreRestart, &release.dir, None)
+        })
+        .and_then(|()| release::mark_built(&release));
+        if let Err(e) = built {
+            // don't leave a half built release to be reused
+            release::remove(target, user, &release);
+            return Err(e);
+        }
     }
+    release::activate(releases, user, &release)?;
+    Ok(release.dir)
+}
 
-    for line in passwd.lines() {
-        let mut parts = line.split(':');
-        let ent = Entry {
-            name: parts
-                .next()
-                .ok_or_else(|| eyre!("error parsing /etc/passwd: name missing"))?,
-            _password: parts
-                .next()
-                .ok_or_else(|| eyre!("error parsing /etc/passwd: password missing"))?,
-            uid: parts
-                .next()
-                .and_then(|s| s.parse().ok())
-                .map(Uid::from_raw)
-                .ok_or_else(|| eyre!("error parsing /etc/passwd: uid missing"))?,
-            _gid: parts
-                .next()
-                .and_then(|s| s.parse().ok())
-                .map(Gid::from_raw)
-                .ok_or_else(|| eyre!("error parsing /etc/passwd: gid missing"))?,
-            _info: parts
-                .next()
-                .ok_or_else(|| eyre!("error parsing /etc/passwd: info/comment missing"))?,
-            _home: parts
-                .next()
-                .map(Path::new)
-                .ok_or_else(|| eyre!("error parsing /etc/passwd: home dir missing"))?,
-            _shell: parts
-                .next()
-                .map(Path::new)
-                .ok_or_else(|| eyre!("error parsing /etc/passwd: login shell missing"))?,
-        };
-        if ent.name == name {
-            tracing::debug!("found user: {:?}", ent);
-            return Ok(ent.uid);
+/// restart the systemd services which code we are watching, skipping the ones
+/// whose paths didn't change
+#[instrument(skip_all)]
+fn restart_services(
+    target: &Target,
+    user: &User,
+    changed: Option<&[String]>,
+) -> color_eyre::Result<()> {
+    for (i, unit) in target.units().into_iter().enumerate() {
+        if !pipeline::touches(&unit.paths, changed)? {
+            tracing::info!("not restarting {}, none of its paths changed", unit.name);
+            continue;
+        }
+        match &target.blue_green {
+            // the first unit is `system_name`
+            Some(config) if i == 0 => blue_green::deploy(target, config, user, &unit)?,
+            _ => units::apply(target, &unit)?,
         }
     }
 
-    Err(eyre!("entry not found"))
+    Ok(())
 }
 
 #[derive(Debug)]
@@ -0,0 +1,58 @@
+use std::{
+    env,
+    os::fd::{FromRawFd, RawFd},
+};
+
+use color_eyre::eyre::Context;
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    sys::socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage},
+};
+use tokio::net::UnixListener;
+use tracing::instrument;
+
+use crate::server::Listener;
+
+/// first file descriptor passed by systemd, see `sd_listen_fds(3)`
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// take the listening sockets passed through socket activation. returns no
+/// sockets unless `LISTEN_PID` is the pid of this process
+#[instrument]
+pub fn listen_fds() -> color_eyre::Result<Vec<Listener>> {
+    let Ok(pid) = env::var("LISTEN_PID") else {
+        return Ok(Vec::new());
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        tracing::debug!("LISTEN_PID {} is for a different process", pid);
+        return Ok(Vec::new());
+    }
+    let count: RawFd = env::var("LISTEN_FDS")
+        .context("LISTEN_PID is set without LISTEN_FDS")?
+        .parse()
+        .context("parsing LISTEN_FDS")?;
+    tracing::debug!("received {} sockets", count);
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // systemd doesn't set close-on-exec, don't leak the sockets to git and hooks
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+                .with_context(|| format!("setting close-on-exec on fd {fd}"))?;
+            let addr: SockaddrStorage =
+                getsockname(fd).with_context(|| format!("getting address of fd {fd}"))?;
+            tracing::debug!("fd {} is listening on {}", fd, addr);
+
+            // safety: systemd passes ownership of the sockets to this process and
+            // each one is only taken once
+            if addr.family() == Some(AddressFamily::Unix) {
+                let uds = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                uds.set_nonblocking(true)?;
+                Ok(Listener::Unix(UnixListener::from_std(uds)?))
+            } else {
+                let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                tcp.set_nonblocking(true)?;
+                Ok(Listener::Tcp(tcp))
+            }
+        })
+        .collect()
+}
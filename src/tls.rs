@@ -0,0 +1,105 @@
+use axum_server::tls_rustls::RustlsConfig;
+use color_eyre::eyre::Context;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::instrument;
+
+use crate::config::TlsConfig;
+
+/// load the certificate and key
+#[instrument(skip_all)]
+pub async fn load(tls: &TlsConfig) -> color_eyre::Result<RustlsConfig> {
+    RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .context("loading tls certificate and key")
+}
+
+/// handle SIGHUP, reloading the certificate and key if tls is used. the handler
+/// is installed even without tls so SIGHUP never stops shook
+pub fn reload_on_hangup(tls: Option<(RustlsConfig, TlsConfig)>) -> color_eyre::Result<()> {
+    let mut hangup = signal(SignalKind::hangup()).context("listening for SIGHUP")?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            let Some((rustls, tls)) = &tls else {
+                tracing::info!("got SIGHUP, there's nothing to reload");
+                continue;
+            };
+            tracing::info!("reloading tls certificate and key");
+            // keep serving the old certificate if the new one is broken
+            if let Err(e) = rustls.reload_from_pem_file(&tls.cert, &tls.key).await {
+                tracing::error!("failed to reload tls certificate and key: {e}");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    use nix::sys::signal::{raise, Signal};
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    /// SIGHUP goes to every handler in the process, so tests raising it can't
+    /// run at the same time
+    static HANGUP: Mutex<()> = Mutex::const_new(());
+
+    /// write a new self-signed certificate and key for localhost
+    fn self_signed(dir: &Path) -> TlsConfig {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let tls = TlsConfig {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+        };
+        std::fs::write(&tls.cert, cert.cert.pem()).unwrap();
+        std::fs::write(&tls.key, cert.key_pair.serialize_pem()).unwrap();
+        tls
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("shook-tls-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn reload_self_signed() {
+        let _hangup = HANGUP.lock().await;
+        let dir = temp_dir("reload");
+        let tls = self_signed(&dir);
+        let rustls = load(&tls).await.unwrap();
+        reload_on_hangup(Some((rustls.clone(), tls.clone()))).unwrap();
+
+        let old = rustls.get_inner();
+        self_signed(&dir);
+        raise(Signal::SIGHUP).unwrap();
+        for _ in 0..50 {
+            if !Arc::ptr_eq(&old, &rustls.get_inner()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!Arc::ptr_eq(&old, &rustls.get_inner()));
+
+        // a missing certificate keeps the old one
+        let current = rustls.get_inner();
+        std::fs::remove_file(&tls.cert).unwrap();
+        raise(Signal::SIGHUP).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(Arc::ptr_eq(&current, &rustls.get_inner()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// without a handler SIGHUP would kill the test process
+    #[tokio::test]
+    async fn hangup_without_tls() {
+        let _hangup = HANGUP.lock().await;
+        reload_on_hangup(None).unwrap();
+        raise(Signal::SIGHUP).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
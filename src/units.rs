@@ -0,0 +1,584 @@
+//! controlling the systemd services of a target, either with `systemctl` or
+//! over d-bus
+use std::{thread, time::Duration};
+
+use color_eyre::eyre::eyre;
+use nix::unistd::Uid;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    config::{Service, Target, UnitAction},
+    process, user,
+};
+
+/// how often to check the state of a service that's starting
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// how shook talks to systemd
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemdBackend {
+    /// run `systemctl`
+    #[default]
+    Systemctl,
+    /// call systemd's d-bus api on the system bus, or the session bus in user
+    /// mode
+    Dbus,
+}
+
+/// the systemd manager units are controlled through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Systemd {
+    pub backend: SystemdBackend,
+    /// the user whose own manager (`systemctl --user`) is used, or `None` for
+    /// the system manager
+    pub user: Option<String>,
+}
+
+impl Systemd {
+    pub fn new(backend: SystemdBackend, user_mode: bool, username: &str) -> Self {
+        Self {
+            backend,
+            user: user_mode.then(|| username.to_string()),
+        }
+    }
+}
+
+/// make sure a manager can be used, so misconfigurations are found when shook
+/// starts instead of on the first deploy
+pub fn check(systemd: &Systemd) -> color_eyre::Result<()> {
+    if systemd.backend == SystemdBackend::Dbus && !cfg!(feature = "dbus") {
+        return Err(eyre!(
+            "the dbus backend needs shook to be built with the dbus feature"
+        ));
+    }
+    let Some(name) = &systemd.user else {
+        return Ok(());
+    };
+    let user = user::user(name)?;
+    let current = Uid::current();
+    if user.uid == current {
+        return Ok(());
+    }
+    if !current.is_root() {
+        return Err(eyre!(
+            "controlling the user services of {name} needs shook to run as that user or as root"
+        ));
+    }
+    if systemd.backend == SystemdBackend::Dbus {
+        return Err(eyre!(
+            "the dbus backend can only reach the user manager of the user shook runs as, \
+            run shook as {name}"
+        ));
+    }
+    Ok(())
+}
+
+/// run a `systemctl` command (e.g. `start` or `reload-or-restart`) on a unit,
+/// waiting for its job to finish
+pub fn systemctl(systemd: &Systemd, command: &str, name: &str) -> color_eyre::Result<()> {
+    check(systemd)?;
+    match systemd.backend {
+        SystemdBackend::Systemctl => cli::systemctl(systemd.user.as_deref(), command, name),
+        #[cfg(feature = "dbus")]
+        SystemdBackend::Dbus => dbus::systemctl(systemd.user.is_some(), command, name),
+        #[cfg(not(feature = "dbus"))]
+        SystemdBackend::Dbus => unreachable!("rejected by check"),
+    }
+}
+
+/// run the service's action, then wait for it to be active if it should
+#[instrument(skip_all, fields(unit = %unit.name))]
+pub fn apply(target: &Target, unit: &Service) -> color_eyre::Result<()> {
+    let systemd = target.systemd();
+    // try-restart leaves a unit that isn't running alone, so it won't become active
+    let stays_inactive = unit.wait_active
+        && unit.action == UnitAction::TryRestart
+        && matches!(
+            active_state(&systemd, &unit.name)?.as_str(),
+            "inactive" | "failed"
+        );
+    let command = unit.action.command();
+    systemctl(&systemd, command, &unit.name)?;
+    tracing::info!("ran {} on {}", command, unit.name);
+
+    if stays_inactive {
+        tracing::info!("{} isn't running, not waiting for it", unit.name);
+    } else if unit.wait_active {
+        wait_active(&systemd, &unit.name)?;
+    }
+    Ok(())
+}
+
+/// the `ActiveState` of a unit, e.g. `active` or `failed`
+fn active_state(systemd: &Systemd, name: &str) -> color_eyre::Result<String> {
+    match systemd.backend {
+        SystemdBackend::Systemctl => cli::active_state(systemd.user.as_deref(), name),
+        #[cfg(feature = "dbus")]
+        SystemdBackend::Dbus => dbus::active_state(systemd.user.is_some(), name),
+        #[cfg(not(feature = "dbus"))]
+        SystemdBackend::Dbus => unreachable!("rejected by check"),
+    }
+}
+
+/// wait until a service is active, failing if it stops or fails instead.
+/// waits until the restart stage times out
+fn wait_active(systemd: &Systemd, name: &str) -> color_eyre::Result<()> {
+    loop {
+        process::check_stopped()?;
+        let state = active_state(systemd, name)?;
+        match state.as_str() {
+            "active" => {
+                tracing::info!("{} is active", name);
+                return Ok(());
+            }
+            "activating" | "reloading" | "deactivating" => thread::sleep(POLL_INTERVAL),
+            state => {
+                match systemd.backend {
+                    SystemdBackend::Systemctl => cli::log_status(systemd.user.as_deref(), name),
+                    #[cfg(feature = "dbus")]
+                    SystemdBackend::Dbus => dbus::log_status(systemd.user.is_some(), name),
+                    #[cfg(not(feature = "dbus"))]
+                    SystemdBackend::Dbus => unreachable!("rejected by check"),
+                }
+                return Err(eyre!("{} is {} instead of active", name, state));
+            }
+        }
+    }
+}
+
+mod cli {
+    use std::{env, process::Command};
+
+    use nix::unistd::Uid;
+
+    use crate::{process, user};
+
+    /// `systemctl` for the system manager, or the manager of `user`
+    fn command(user: Option<&str>) -> color_eyre::Result<Command> {
+        let mut command = Command::new("systemctl");
+        let Some(name) = user else {
+            return Ok(command);
+        };
+        let user = user::user(name)?;
+        command.arg("--user");
+        if user.uid != Uid::current() {
+            // root reaches other users' managers through the local container,
+            // like `systemctl --user --machine=app@`
+            command.arg(format!("--machine={}@", user.name));
+        } else if env::var_os("XDG_RUNTIME_DIR").is_none() {
+            // the user's bus is found through this, which is only set in login
+            // sessions and user services
+            command.env("XDG_RUNTIME_DIR", format!("/run/user/{}", user.uid));
+        }
+        Ok(command)
+    }
+
+    pub fn systemctl(user: Option<&str>, command: &str, name: &str) -> color_eyre::Result<()> {
+        process::run(self::command(user)?.arg(command).arg(name), "systemctl")
+    }
+
+    /// the `ActiveState` of a unit, e.g. `active` or `failed`
+    pub fn active_state(user: Option<&str>, name: &str) -> color_eyre::Result<String> {
+        let out = process::output(
+            command(user)?
+                .args(["show", "--property=ActiveState", "--value"])
+                .arg(name),
+            "systemctl show",
+        )?;
+        Ok(out.trim().to_string())
+    }
+
+    /// log the status and latest logs of a unit that didn't start
+    pub fn log_status(user: Option<&str>, name: &str) {
+        let Ok(mut command) = command(user) else {
+            return;
+        };
+        // exits with an error for units that aren't running, which is expected here
+        let _ = process::run(
+            command
+                .args(["status", "--no-pager", "--lines=20"])
+                .arg(name),
+            "systemctl status",
+        );
+    }
+}
+
+#[cfg(feature = "dbus")]
+mod dbus {
+    use std::future::Future;
+
+    use color_eyre::eyre::{eyre, Context};
+    use futures::StreamExt;
+    use tokio::runtime::{Builder, Handle};
+    use zbus::{
+        proxy::{CacheProperties, SignalStream},
+        zvariant::OwnedObjectPath,
+        Connection, Message, Proxy,
+    };
+
+    use super::POLL_INTERVAL;
+    use crate::process;
+
+    const SYSTEMD: &str = "org.freedesktop.systemd1";
+    const MANAGER_PATH: &str = "/org/freedesktop/systemd1";
+    const MANAGER: &str = "org.freedesktop.systemd1.Manager";
+    const UNIT: &str = "org.freedesktop.systemd1.Unit";
+    const SERVICE: &str = "org.freedesktop.systemd1.Service";
+
+    pub fn systemctl(user: bool, command: &str, name: &str) -> color_eyre::Result<()> {
+        block_on(run(user, command, name))
+    }
+
+    pub fn active_state(user: bool, name: &str) -> color_eyre::Result<String> {
+        block_on(async {
+            let conn = connect(user).await?;
+            let unit = unit(&conn, name).await?;
+            Ok(unit.get_property("ActiveState").await?)
+        })
+    }
+
+    pub fn log_status(user: bool, name: &str) {
+        match block_on(async { status(&connect(user).await?, name).await }) {
+            Ok(status) => tracing::error!("{} is {}", name, status),
+            Err(e) => tracing::error!("failed to get the status of {name}: {e}"),
+        }
+    }
+
+    /// run a future on the current runtime, or a new one if there isn't one.
+    /// the future runs on this thread, so it sees its deploy's timeouts
+    fn block_on<T>(future: impl Future<Output = color_eyre::Result<T>>) -> color_eyre::Result<T> {
+        match Handle::try_current() {
+            Ok(handle) => handle.block_on(future),
+            Err(_) => Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .context("starting d-bus runtime")?
+                .block_on(future),
+        }
+    }
+
+    /// connect to the system bus, or `DBUS_SYSTEM_BUS_ADDRESS` if it's set. the
+    /// user manager is on the session bus of the user shook runs as
+    async fn connect(user: bool) -> color_eyre::Result<Connection> {
+        if user {
+            return Connection::session()
+                .await
+                .context("connecting to the session bus");
+        }
+        Connection::system()
+            .await
+            .context("connecting to the system bus")
+    }
+
+    /// a proxy reading properties straight from systemd, since they change
+    /// while waiting on them
+    async fn proxy<'a>(
+        conn: &Connection,
+        path: impl Into<String>,
+        interface: &'static str,
+    ) -> color_eyre::Result<Proxy<'a>> {
+        Ok(zbus::proxy::Builder::new(conn)
+            .destination(SYSTEMD)?
+            .path(path.into())?
+            .interface(interface)?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?)
+    }
+
+    /// the unit object of a loaded unit
+    async fn unit<'a>(conn: &Connection, name: &str) -> color_eyre::Result<Proxy<'a>> {
+        let manager = proxy(conn, MANAGER_PATH, MANAGER).await?;
+        let path: OwnedObjectPath = manager
+            .call("LoadUnit", &(name,))
+            .await
+            .with_context(|| format!("loading unit {name}"))?;
+        proxy(conn, path.as_str(), UNIT).await
+    }
+
+    /// describe a unit's state like `failed (failed, result: exit-code)`
+    async fn status(conn: &Connection, name: &str) -> color_eyre::Result<String> {
+        let unit = unit(conn, name).await?;
+        let active: String = unit.get_property("ActiveState").await?;
+        let sub: String = unit.get_property("SubState").await?;
+        // only services have a result
+        let service = proxy(conn, unit.path().as_str(), SERVICE).await?;
+        Ok(match service.get_property::<String>("Result").await {
+            Ok(result) => format!("{active} ({sub}, result: {result})"),
+            Err(_) => format!("{active} ({sub})"),
+        })
+    }
+
+    /// start the job for a `systemctl` command and wait for it to finish
+    async fn run(user: bool, command: &str, name: &str) -> color_eyre::Result<()> {
+        let conn = connect(user).await?;
+        let manager = proxy(&conn, MANAGER_PATH, MANAGER).await?;
+        if command == "enable" {
+            let _: (bool, Vec<(String, String, String)>) = manager
+                .call("EnableUnitFiles", &(vec![name], false, false))
+                .await
+                .with_context(|| format!("enabling {name}"))?;
+            // like systemctl, so systemd sees the new symlinks
+            manager.call::<_, _, ()>("Reload", &()).await?;
+            return Ok(());
+        }
+        let method = match command {
+            "start" => "StartUnit",
+            "stop" => "StopUnit",
+            "restart" => "RestartUnit",
+            "reload" => "ReloadUnit",
+            "reload-or-restart" => "ReloadOrRestartUnit",
+            "try-restart" => "TryRestartUnit",
+            _ => return Err(eyre!("{command} isn't supported by the dbus backend")),
+        };
+
+        // subscribe before queueing the job, so its JobRemoved signal isn't missed
+        manager.call::<_, _, ()>("Subscribe", &()).await?;
+        let mut removed = manager.receive_signal("JobRemoved").await?;
+        let job: OwnedObjectPath = manager
+            .call(method, &(name, "replace"))
+            .await
+            .with_context(|| format!("calling {method} for {name}"))?;
+        tracing::debug!("queued job {} for {}", job.as_str(), name);
+
+        let result = loop {
+            let message = next_signal(&mut removed).await?;
+            let (_, path, _, result): (u32, OwnedObjectPath, String, String) =
+                message.body().deserialize()?;
+            if path == job {
+                break result;
+            }
+        };
+        if result != "done" {
+            let status = status(&conn, name)
+                .await
+                .unwrap_or_else(|e| format!("in an unknown state ({e})"));
+            return Err(eyre!(
+                "{command} of {name} ended with {result}, it's {status}"
+            ));
+        }
+        Ok(())
+    }
+
+    /// wait for the next signal, giving up once the deploy times out or is
+    /// cancelled
+    async fn next_signal(signals: &mut SignalStream<'_>) -> color_eyre::Result<Message> {
+        loop {
+            process::check_stopped()?;
+            match tokio::time::timeout(POLL_INTERVAL, signals.next()).await {
+                Ok(Some(message)) => return Ok(message),
+                Ok(None) => return Err(eyre!("the system bus closed the connection")),
+                Err(_) => {}
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{
+            env,
+            io::{BufRead, BufReader},
+            path::PathBuf,
+            process::{Child, Command, Stdio},
+            sync::{
+                atomic::{AtomicU32, Ordering},
+                Mutex,
+            },
+            thread,
+            time::Duration,
+        };
+
+        use zbus::{interface, object_server::SignalEmitter, ObjectServer};
+
+        use super::*;
+
+        /// tests point `DBUS_SYSTEM_BUS_ADDRESS` at their own bus, so they
+        /// can't run at the same time
+        static BUS: Mutex<()> = Mutex::new(());
+
+        /// a private dbus-daemon with a fake systemd on it. units with `bad`
+        /// in their name fail
+        struct Bus {
+            daemon: Child,
+            dir: PathBuf,
+        }
+
+        impl Bus {
+            fn start(name: &str) -> Self {
+                let dir = env::temp_dir().join(format!("shook-dbus-{}-{name}", std::process::id()));
+                std::fs::create_dir_all(&dir).unwrap();
+                let mut daemon = Command::new("dbus-daemon")
+                    .arg("--session")
+                    .arg("--nofork")
+                    .arg("--print-address")
+                    .arg(format!("--address=unix:path={}", dir.join("bus").display()))
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .spawn()
+                    .expect("dbus-daemon has to be installed to test the dbus backend");
+                // the address is printed once it's listening
+                let mut address = String::new();
+                BufReader::new(daemon.stdout.take().unwrap())
+                    .read_line(&mut address)
+                    .unwrap();
+                let address = address.trim().to_string();
+                env::set_var("DBUS_SYSTEM_BUS_ADDRESS", &address);
+
+                let (ready, wait) = std::sync::mpsc::channel();
+                thread::spawn(move || {
+                    tokio::runtime::Runtime::new()
+                        .unwrap()
+                        .block_on(async move {
+                            let _conn = zbus::connection::Builder::address(address.as_str())
+                                .unwrap()
+                                .name(SYSTEMD)
+                                .unwrap()
+                                .serve_at(MANAGER_PATH, Manager)
+                                .unwrap()
+                                .build()
+                                .await
+                                .unwrap();
+                            ready.send(()).unwrap();
+                            std::future::pending::<()>().await
+                        })
+                });
+                wait.recv().unwrap();
+                Self { daemon, dir }
+            }
+        }
+
+        impl Drop for Bus {
+            fn drop(&mut self) {
+                let _ = self.daemon.kill();
+                let _ = self.daemon.wait();
+                let _ = std::fs::remove_dir_all(&self.dir);
+            }
+        }
+
+        static JOB: AtomicU32 = AtomicU32::new(1);
+
+        struct Manager;
+
+        #[interface(name = "org.freedesktop.systemd1.Manager")]
+        impl Manager {
+            async fn subscribe(&self) {}
+
+            async fn load_unit(
+                &self,
+                name: &str,
+                #[zbus(object_server)] server: &ObjectServer,
+            ) -> OwnedObjectPath {
+                let path = format!("{MANAGER_PATH}/unit/{}", name.replace(['.', '-'], "_"));
+                let failed = name.contains("bad");
+                server.at(path.as_str(), Unit { failed }).await.unwrap();
+                server.at(path.as_str(), Service { failed }).await.unwrap();
+                OwnedObjectPath::try_from(path).unwrap()
+            }
+
+            async fn restart_unit(
+                &self,
+                name: &str,
+                _mode: &str,
+                #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+            ) -> OwnedObjectPath {
+                let job =
+                    |id| OwnedObjectPath::try_from(format!("{MANAGER_PATH}/job/{id}")).unwrap();
+                let (other, id) = (
+                    JOB.fetch_add(1, Ordering::SeqCst),
+                    JOB.fetch_add(1, Ordering::SeqCst),
+                );
+                let result = if name.contains("bad") {
+                    "failed"
+                } else {
+                    "done"
+                };
+                let (name, emitter) = (name.to_string(), emitter.to_owned());
+                // finish after replying, with an unrelated job finishing first
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Manager::job_removed(&emitter, other, job(other), "other.service", "failed")
+                        .await
+                        .unwrap();
+                    Manager::job_removed(&emitter, id, job(id), &name, result)
+                        .await
+                        .unwrap();
+                });
+                job(id)
+            }
+
+            #[zbus(signal)]
+            async fn job_removed(
+                emitter: &SignalEmitter<'_>,
+                id: u32,
+                job: OwnedObjectPath,
+                unit: &str,
+                result: &str,
+            ) -> zbus::Result<()>;
+        }
+
+        struct Unit {
+            failed: bool,
+        }
+
+        #[interface(name = "org.freedesktop.systemd1.Unit")]
+        impl Unit {
+            #[zbus(property)]
+            fn active_state(&self) -> &str {
+                if self.failed {
+                    "failed"
+                } else {
+                    "active"
+                }
+            }
+
+            #[zbus(property)]
+            fn sub_state(&self) -> &str {
+                if self.failed {
+                    "failed"
+                } else {
+                    "running"
+                }
+            }
+        }
+
+        struct Service {
+            failed: bool,
+        }
+
+        #[interface(name = "org.freedesktop.systemd1.Service")]
+        impl Service {
+            #[zbus(property)]
+            fn result(&self) -> &str {
+                if self.failed {
+                    "exit-code"
+                } else {
+                    "success"
+                }
+            }
+        }
+
+        #[test]
+        fn restart() {
+            let _lock = BUS.lock().unwrap();
+            let _bus = Bus::start("restart");
+
+            systemctl(false, "restart", "app.service").unwrap();
+            assert_eq!(active_state(false, "app.service").unwrap(), "active");
+        }
+
+        #[test]
+        fn restart_failed() {
+            let _lock = BUS.lock().unwrap();
+            let _bus = Bus::start("restart-failed");
+
+            let e = systemctl(false, "restart", "bad.service").unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "restart of bad.service ended with failed, it's failed (failed, result: exit-code)"
+            );
+            assert_eq!(active_state(false, "bad.service").unwrap(), "failed");
+        }
+    }
+}
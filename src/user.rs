@@ -0,0 +1,45 @@
+//! user and group lookups through nss, so accounts from ldap, sssd or
+//! systemd-homed are found as well as the ones in `/etc/passwd`
+use color_eyre::eyre::{eyre, Context};
+use nix::unistd::{Gid, Group, Uid, User};
+use tracing::instrument;
+
+/// get the uid of a user name or numeric uid. numeric uids don't need an
+/// account, which is fine for changing file ownership
+#[instrument]
+pub fn user_id(name: &str) -> color_eyre::Result<Uid> {
+    if let Ok(uid) = name.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+
+    Ok(user(name)?.uid)
+}
+
+/// get the gid of a group name or numeric gid
+#[instrument]
+pub fn group_id(name: &str) -> color_eyre::Result<Gid> {
+    if let Ok(gid) = name.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+
+    let group = Group::from_name(name)
+        .with_context(|| format!("looking up group {name}"))?
+        .ok_or_else(|| eyre!("group not found: {name}"))?;
+    tracing::debug!("found group: {:?}", group);
+
+    Ok(group.gid)
+}
+
+/// get the account of a user name or numeric uid
+#[instrument]
+pub fn user(name: &str) -> color_eyre::Result<User> {
+    let user = match name.parse() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(name),
+    }
+    .with_context(|| format!("looking up user {name}"))?
+    .ok_or_else(|| eyre!("user not found: {name}"))?;
+    tracing::debug!("found user: {} ({})", user.name, user.uid);
+
+    Ok(user)
+}
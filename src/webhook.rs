@@ -0,0 +1,224 @@
+use axum::{
+    async_trait,
+    body::Body,
+    extract::FromRequest,
+    http::{self, StatusCode},
+};
+use github_webhook_extract::{
+    BitbucketEvent, BitbucketPayload, CloudRef, Event, EventDiscriminants, GiteaPayload,
+    GithubPayload,
+};
+use uuid::Uuid;
+
+use crate::config::Provider;
+
+/// hash providers use for the missing side of a created or deleted ref
+pub const NULL_HASH: &str = "0000000000000000000000000000000000000000";
+
+/// a verified webhook delivery from any supported provider
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub guid: Uuid,
+    pub provider: Provider,
+    pub event: EventDiscriminants,
+    /// refs updated by a push event, empty for other events
+    pub refs: Vec<RefUpdate>,
+}
+
+/// a single ref moved by a push
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefUpdate {
+    /// full name of the ref, e.g. `refs/heads/main`
+    pub r#ref: String,
+    pub before: String,
+    pub after: String,
+    pub forced: bool,
+}
+
+impl RefUpdate {
+    /// check if the push deleted the ref
+    pub fn is_deleted(&self) -> bool {
+        self.after == NULL_HASH
+    }
+}
+
+impl Delivery {
+    /// get the update to `branch` if this delivery pushed to it
+    pub fn branch_update(&self, branch: &str) -> Option<&RefUpdate> {
+        let r#ref = format!("refs/heads/{branch}");
+        self.refs.iter().find(|u| u.r#ref == r#ref)
+    }
+
+    fn from_event(guid: Uuid, provider: Provider, event: Event) -> Self {
+        let refs = match &event {
+            Event::Push {
+                r#ref,
+                before,
+                after,
+                forced,
+                ..
+            } => vec![RefUpdate {
+                r#ref: r#ref.clone(),
+                before: before.clone(),
+                after: after.clone(),
+                forced: *forced,
+            }],
+            _ => Vec::new(),
+        };
+
+        Self {
+            guid,
+            provider,
+            event: event.into(),
+            refs,
+        }
+    }
+}
+
+impl From<GithubPayload> for Delivery {
+    fn from(payload: GithubPayload) -> Self {
+        Self::from_event(payload.guid, Provider::Github, payload.event)
+    }
+}
+
+impl From<GiteaPayload> for Delivery {
+    fn from(payload: GiteaPayload) -> Self {
+        Self::from_event(payload.guid, Provider::Gitea, payload.event)
+    }
+}
+
+impl From<BitbucketPayload> for Delivery {
+    fn from(payload: BitbucketPayload) -> Self {
+        let (event, refs) = match payload.event {
+            BitbucketEvent::CloudPush(push) => {
+                let refs = push
+                    .push
+                    .changes
+                    .into_iter()
+                    .filter_map(|change| {
+                        let r#ref = change.new.as_ref().or(change.old.as_ref())?.full_name();
+                        let hash = |r: Option<CloudRef>| {
+                            r.map(|r| r.target.hash)
+                                .unwrap_or_else(|| NULL_HASH.to_string())
+                        };
+                        Some(RefUpdate {
+                            r#ref,
+                            before: hash(change.old),
+                            after: hash(change.new),
+                            forced: change.forced,
+                        })
+                    })
+                    .collect();
+                (EventDiscriminants::Push, refs)
+            }
+            BitbucketEvent::ServerPush(push) => {
+                let refs = push
+                    .changes
+                    .into_iter()
+                    .map(|change| RefUpdate {
+                        r#ref: change.ref_id,
+                        before: change.from_hash,
+                        after: change.to_hash,
+                        forced: false,
+                    })
+                    .collect();
+                (EventDiscriminants::Push, refs)
+            }
+            BitbucketEvent::Ping {} => (EventDiscriminants::Ping, Vec::new()),
+        };
+
+        Self {
+            guid: payload.guid,
+            provider: Provider::Bitbucket,
+            event,
+            refs,
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequest<S, Body> for Delivery
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request(req: http::Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        // gitea also sends github headers so it has to be checked first
+        let headers = req.headers();
+        if headers.contains_key("X-Gitea-Event") || headers.contains_key("X-Forgejo-Event") {
+            tracing::debug!("extracting gitea payload");
+            return GiteaPayload::from_request(req, state).await.map(Into::into);
+        }
+        if headers.contains_key("X-Event-Key") {
+            tracing::debug!("extracting bitbucket payload");
+            return BitbucketPayload::from_request(req, state)
+                .await
+                .map(Into::into);
+        }
+
+        GithubPayload::from_request(req, state)
+            .await
+            .map(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitbucket(event: &str, body: &str) -> Delivery {
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        let event = serde_json::from_value(serde_json::json!({ event: json })).unwrap();
+        BitbucketPayload {
+            guid: Uuid::nil(),
+            signature: None,
+            event,
+        }
+        .into()
+    }
+
+    #[test]
+    fn bitbucket_cloud_push() {
+        let delivery = bitbucket(
+            "repo:push",
+            include_str!("../github-webhook-extract/testdata/bitbucket-cloud-push.json"),
+        );
+        assert_eq!(delivery.event, EventDiscriminants::Push);
+        assert_eq!(
+            delivery.branch_update("main"),
+            Some(&RefUpdate {
+                r#ref: "refs/heads/main".to_string(),
+                before: "69276a2dcf41af30db542f97a5f75cdac86f1197".to_string(),
+                after: "056f7d95bf8857db90986202dc9ed3e6d94f55a8".to_string(),
+                forced: false,
+            })
+        );
+        assert!(delivery.branch_update("old-feature").unwrap().is_deleted());
+    }
+
+    #[test]
+    fn bitbucket_server_refs_changed() {
+        let delivery = bitbucket(
+            "repo:refs_changed",
+            include_str!("../github-webhook-extract/testdata/bitbucket-server-refs-changed.json"),
+        );
+        assert_eq!(delivery.event, EventDiscriminants::Push);
+        assert_eq!(
+            delivery.branch_update("master"),
+            Some(&RefUpdate {
+                r#ref: "refs/heads/master".to_string(),
+                before: "ecddabb624f6f5ba43816f5926e580a5f680a932".to_string(),
+                after: "178864a7d521b6f5e720b386b2c2b0ef8563e0dc".to_string(),
+                forced: false,
+            })
+        );
+    }
+
+    #[test]
+    fn bitbucket_ping() {
+        let delivery = bitbucket("diagnostics:ping", r#"{"test": true}"#);
+        assert_eq!(delivery.event, EventDiscriminants::Ping);
+        assert!(delivery.refs.is_empty());
+    }
+}